@@ -1,4 +1,4 @@
-use sdl2::audio::AudioQueue;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 
 const MASTER_VOLUME: f32 = 0.05;
 const DUTY_TABLE: [[u8; 8]; 4] = [
@@ -12,7 +12,9 @@ const DIVIDER_TABLE: [u8; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
 pub struct APU {
     prev_div: u8,
     counter: u8,
-    device: AudioQueue<f32>,
+    sample_rate: i32,
+    sample_fraction: i32,
+    samples: Vec<f32>,
 
     pub global: Global,
     pub ch1: Ch1,
@@ -22,11 +24,13 @@ pub struct APU {
 }
 
 impl APU {
-    pub fn new(device: AudioQueue<f32>) -> Self {
+    pub fn new(sample_rate: i32) -> Self {
         Self {
             prev_div: 0,
             counter: 0,
-            device,
+            sample_rate,
+            sample_fraction: 0,
+            samples: Vec::new(),
 
             global: Global::new(),
             ch1: Ch1::new(),
@@ -66,23 +70,21 @@ impl APU {
             self.ch4.tick_envelope();
         }
 
-        let freq = self.device.spec().freq;
-        let must_add = freq / 512; // 絶対に作らないといけないサイズ
+        let freq = self.sample_rate;
         let max_buffer_size = freq / 60 * 6; // 6フレーム
-        let min_buffer_size = freq / 60 * 3; // 3フレーム
-        let curret_buffer_size = self.device.size() as i32 / 4 / 2; // f32=4byte, 2 channle
+        let curret_buffer_size = self.samples.len() as i32 / 2; // 2 channle
+
+        // 1/512秒分のサンプルを作る。端数は次回に持ち越す。
+        self.sample_fraction += freq;
+        let add_size = self.sample_fraction / 512;
+        self.sample_fraction %= 512;
 
         if curret_buffer_size > max_buffer_size {
+            // 取り出されていないので捨てる
             return;
         }
 
-        let add_size = if curret_buffer_size < min_buffer_size {
-            min_buffer_size - curret_buffer_size
-        } else {
-            must_add
-        };
-
-        let mut wave = Vec::with_capacity(add_size as usize * 2);
+        let wave = &mut self.samples;
         for _ in 0..add_size {
             let ch1 = self.ch1.next(freq) * MASTER_VOLUME;
             let ch2 = self.ch2.next(freq) * MASTER_VOLUME;
@@ -113,8 +115,36 @@ impl APU {
             self.global.ch3_power = self.ch3.enabled;
             self.global.ch4_power = self.ch4.enabled;
         }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.prev_div);
+        w.write_u8(self.counter);
+        w.write_u32(self.sample_fraction as u32);
+        self.global.save_state(w);
+        self.ch1.save_state(w);
+        self.ch2.save_state(w);
+        self.ch3.save_state(w);
+        self.ch4.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.prev_div = r.read_u8()?;
+        self.counter = r.read_u8()?;
+        self.sample_fraction = r.read_u32()? as i32;
+        self.global.load_state(r)?;
+        self.ch1.load_state(r)?;
+        self.ch2.load_state(r)?;
+        self.ch3.load_state(r)?;
+        self.ch4.load_state(r)?;
+        // 古い音が残らないように捨てる
+        self.samples.clear();
+        Ok(())
+    }
 
-        self.device.queue_audio(&wave).unwrap();
+    // 生成済みのサンプル (L, R, L, R, ...) を取り出す
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
     }
 }
 
@@ -161,6 +191,27 @@ impl Global {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.nr52);
+        w.write_u8(self.nr51);
+        w.write_u8(self.nr50);
+        w.write_bool(self.ch1_power);
+        w.write_bool(self.ch2_power);
+        w.write_bool(self.ch3_power);
+        w.write_bool(self.ch4_power);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.nr52 = r.read_u8()?;
+        self.nr51 = r.read_u8()?;
+        self.nr50 = r.read_u8()?;
+        self.ch1_power = r.read_bool()?;
+        self.ch2_power = r.read_bool()?;
+        self.ch3_power = r.read_bool()?;
+        self.ch4_power = r.read_bool()?;
+        Ok(())
+    }
+
     pub fn power(&self) -> bool {
         self.nr52 & 0x80 != 0
     }
@@ -289,6 +340,37 @@ impl Ch1 {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.nr10);
+        w.write_u8(self.nr11);
+        w.write_u8(self.nr12);
+        w.write_u8(self.nr13);
+        w.write_u8(self.nr14);
+        w.write_f32(self.phase);
+        w.write_u8(self.sweep_pace);
+        w.write_u16(self.current_period);
+        w.write_u8(self.volume);
+        w.write_u8(self.envelope_pace);
+        w.write_u8(self.length_counter);
+        w.write_bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.nr10 = r.read_u8()?;
+        self.nr11 = r.read_u8()?;
+        self.nr12 = r.read_u8()?;
+        self.nr13 = r.read_u8()?;
+        self.nr14 = r.read_u8()?;
+        self.phase = r.read_f32()?;
+        self.sweep_pace = r.read_u8()?;
+        self.current_period = r.read_u16()?;
+        self.volume = r.read_u8()?;
+        self.envelope_pace = r.read_u8()?;
+        self.length_counter = r.read_u8()?;
+        self.enabled = r.read_bool()?;
+        Ok(())
+    }
+
     pub fn pace(&self) -> u8 {
         (self.nr10 & 0x70) >> 4
     }
@@ -481,6 +563,33 @@ impl Ch2 {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.nr21);
+        w.write_u8(self.nr22);
+        w.write_u8(self.nr23);
+        w.write_u8(self.nr24);
+        w.write_f32(self.phase);
+        w.write_u16(self.current_period);
+        w.write_u8(self.volume);
+        w.write_u8(self.envelope_pace);
+        w.write_u8(self.length_counter);
+        w.write_bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.nr21 = r.read_u8()?;
+        self.nr22 = r.read_u8()?;
+        self.nr23 = r.read_u8()?;
+        self.nr24 = r.read_u8()?;
+        self.phase = r.read_f32()?;
+        self.current_period = r.read_u16()?;
+        self.volume = r.read_u8()?;
+        self.envelope_pace = r.read_u8()?;
+        self.length_counter = r.read_u8()?;
+        self.enabled = r.read_bool()?;
+        Ok(())
+    }
+
     pub fn duty(&self) -> u8 {
         (self.nr21 & 0xC0) >> 6
     }
@@ -631,6 +740,31 @@ impl Ch3 {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.nr30);
+        w.write_u8(self.nr31);
+        w.write_u8(self.nr32);
+        w.write_u8(self.nr33);
+        w.write_u8(self.nr34);
+        w.write_bytes(&self.waveform);
+        w.write_f32(self.phase);
+        w.write_u8(self.length_counter);
+        w.write_bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.nr30 = r.read_u8()?;
+        self.nr31 = r.read_u8()?;
+        self.nr32 = r.read_u8()?;
+        self.nr33 = r.read_u8()?;
+        self.nr34 = r.read_u8()?;
+        r.read_bytes(&mut self.waveform)?;
+        self.phase = r.read_f32()?;
+        self.length_counter = r.read_u8()?;
+        self.enabled = r.read_bool()?;
+        Ok(())
+    }
+
     pub fn initial_length(&self) -> u8 {
         self.nr31
     }
@@ -764,6 +898,37 @@ impl Ch4 {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.nr41);
+        w.write_u8(self.nr42);
+        w.write_u8(self.nr43);
+        w.write_u8(self.nr44);
+        w.write_f32(self.phase);
+        w.write_u8(self.volume);
+        w.write_u8(self.envelope_pace);
+        w.write_u8(self.length_counter);
+        w.write_u16(self.lfsr);
+        w.write_u32(self.timer_counter);
+        w.write_u16(self.value);
+        w.write_bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.nr41 = r.read_u8()?;
+        self.nr42 = r.read_u8()?;
+        self.nr43 = r.read_u8()?;
+        self.nr44 = r.read_u8()?;
+        self.phase = r.read_f32()?;
+        self.volume = r.read_u8()?;
+        self.envelope_pace = r.read_u8()?;
+        self.length_counter = r.read_u8()?;
+        self.lfsr = r.read_u16()?;
+        self.timer_counter = r.read_u32()?;
+        self.value = r.read_u16()?;
+        self.enabled = r.read_bool()?;
+        Ok(())
+    }
+
     pub fn initial_length(&self) -> u8 {
         self.nr41 & 0x3F
     }
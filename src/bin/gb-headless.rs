@@ -0,0 +1,313 @@
+// SDLを使わずにROMを実行する。CIでテストROMを回すためのもの。
+//
+//   gb-headless --frames 600 --png out.png rom/dmg-acid2.gb
+//   gb-headless --until-serial Passed "rom/cpu_instrs/01-special.gb"
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use gameboy_emulator::cartridge::{Cartridge, LoadOptions, PatchFile, SaveMismatch};
+use gameboy_emulator::cpu::HardwareMode;
+use gameboy_emulator::{Emulator, EmulatorEvent, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const USAGE: &str = "usage: gb-headless [OPTIONS] <ROM>
+
+options:
+    --frames <N>                 maximum number of frames to run (default: 600)
+    --mode <dmg|cgb>             hardware to emulate (default: cgb)
+    --png <FILE>                 write the last frame as PNG
+    --serial <FILE>              write bytes sent to the serial port (default: stdout)
+    --until-serial <TEXT>        stop when the serial output contains TEXT
+    --until-memory <ADDR=VALUE>  stop when the byte at ADDR (hex) equals VALUE (hex)
+    --until-mooneye              stop when a Mooneye test reports pass/fail on serial
+    --entry <NAME>               file to load from a .zip (default: the first .gb/.gbc)
+    --patch <FILE>               apply an IPS/UPS/BPS patch (default: a same-named patch next to the ROM)
+    --no-patch                   do not apply a patch
+    --save-dir <DIR>             directory for save files (default: next to the ROM)
+    --save-mismatch <fail|resize|backup>
+                                 what to do when the save file size does not match (default: fail)
+    --trace <FILE>               write a gameboy-doctor style log of every instruction to FILE
+    -h, --help                   print this help
+
+exit status is 0 when a stop condition was met (or none was given), 1 otherwise.
+a Mooneye failure report also exits with 1.";
+
+// Mooneye のテストROMは、成功時にフィボナッチ数を、失敗時に0x42をシリアルに送る
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+struct Options {
+    rom: PathBuf,
+    frames: u32,
+    mode: HardwareMode,
+    png: Option<PathBuf>,
+    serial: Option<PathBuf>,
+    until_serial: Vec<String>,
+    until_memory: Vec<(u16, u8)>,
+    until_mooneye: bool,
+    entry: Option<String>,
+    patch: PatchFile,
+    save_dir: Option<PathBuf>,
+    save_mismatch: SaveMismatch,
+    trace: Option<PathBuf>,
+}
+
+impl Options {
+    // -h / --help の場合は None
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Self>, String> {
+        let mut options = Options {
+            rom: PathBuf::new(),
+            frames: 600,
+            mode: HardwareMode::CGB,
+            png: None,
+            serial: None,
+            until_serial: Vec::new(),
+            until_memory: Vec::new(),
+            until_mooneye: false,
+            entry: None,
+            patch: PatchFile::Auto,
+            save_dir: None,
+            save_mismatch: SaveMismatch::Fail,
+            trace: None,
+        };
+        let mut rom = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--frames" => {
+                    let value = value(&mut args, "--frames")?;
+                    options.frames = value
+                        .parse()
+                        .map_err(|_| format!("invalid frame count: {}", value))?;
+                }
+                "--mode" => {
+                    let value = value(&mut args, "--mode")?;
+                    options.mode = match value.to_ascii_lowercase().as_str() {
+                        "dmg" => HardwareMode::DMG,
+                        "cgb" => HardwareMode::CGB,
+                        _ => return Err(format!("invalid mode: {} (dmg or cgb)", value)),
+                    };
+                }
+                "--png" => options.png = Some(PathBuf::from(value(&mut args, "--png")?)),
+                "--serial" => options.serial = Some(PathBuf::from(value(&mut args, "--serial")?)),
+                "--until-serial" => options
+                    .until_serial
+                    .push(value(&mut args, "--until-serial")?),
+                "--until-memory" => {
+                    let value = value(&mut args, "--until-memory")?;
+                    options.until_memory.push(
+                        parse_memory_condition(&value)
+                            .ok_or_else(|| format!("invalid memory condition: {}", value))?,
+                    );
+                }
+                "--until-mooneye" => options.until_mooneye = true,
+                "--entry" => options.entry = Some(value(&mut args, "--entry")?),
+                "--patch" => {
+                    options.patch = PatchFile::Path(PathBuf::from(value(&mut args, "--patch")?))
+                }
+                "--no-patch" => options.patch = PatchFile::Off,
+                "--save-dir" => {
+                    options.save_dir = Some(PathBuf::from(value(&mut args, "--save-dir")?))
+                }
+                "--save-mismatch" => {
+                    let value = value(&mut args, "--save-mismatch")?;
+                    options.save_mismatch = match value.to_ascii_lowercase().as_str() {
+                        "fail" => SaveMismatch::Fail,
+                        "resize" => SaveMismatch::Resize,
+                        "backup" => SaveMismatch::Backup,
+                        _ => {
+                            return Err(format!(
+                                "invalid save mismatch: {} (fail, resize or backup)",
+                                value
+                            ))
+                        }
+                    };
+                }
+                "--trace" => options.trace = Some(PathBuf::from(value(&mut args, "--trace")?)),
+                _ if arg.starts_with('-') => {
+                    return Err(format!("unknown option: {}\n\n{}", arg, USAGE))
+                }
+                _ => {
+                    if rom.is_some() {
+                        return Err(format!("unexpected argument: {}\n\n{}", arg, USAGE));
+                    }
+                    rom = Some(PathBuf::from(arg));
+                }
+            }
+        }
+
+        options.rom = rom.ok_or_else(|| format!("no ROM file given\n\n{}", USAGE))?;
+        if !options.rom.is_file() {
+            return Err(format!("ROM file not found: {}", options.rom.display()));
+        }
+        Ok(Some(options))
+    }
+
+    fn has_condition(&self) -> bool {
+        !self.until_serial.is_empty() || !self.until_memory.is_empty() || self.until_mooneye
+    }
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("missing value for {}", name))
+}
+
+// "C000=01" / "0xC000=0x01"
+fn parse_memory_condition(value: &str) -> Option<(u16, u8)> {
+    let (addr, byte) = value.split_once('=')?;
+    let hex = |s: &str| {
+        s.trim_start_matches("0x")
+            .trim_start_matches("0X")
+            .to_string()
+    };
+    let addr = u16::from_str_radix(&hex(addr), 16).ok()?;
+    let byte = u8::from_str_radix(&hex(byte), 16).ok()?;
+    Some((addr, byte))
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    !needle.is_empty() && haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn condition_met(emulator: &mut Emulator, options: &Options) -> bool {
+    let serial = emulator.serial_output();
+    if options
+        .until_serial
+        .iter()
+        .any(|text| contains(serial, text.as_bytes()))
+    {
+        return true;
+    }
+    if options.until_mooneye && (contains(serial, &MOONEYE_PASS) || contains(serial, &MOONEYE_FAIL))
+    {
+        return true;
+    }
+    options
+        .until_memory
+        .iter()
+        .any(|&(addr, value)| emulator.read_memory(addr) == value)
+}
+
+fn write_png(path: &Path, frame: &[u8]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    writer
+        .write_image_data(frame)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+
+    let load_options = LoadOptions {
+        save_dir: options.save_dir.clone(),
+        archive_entry: options.entry.clone(),
+        patch: options.patch.clone(),
+        save_mismatch: options.save_mismatch,
+        ..LoadOptions::default()
+    };
+    let cartridge = match Cartridge::load_with(&options.rom, &load_options) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("failed to load ROM: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Some(path) = cartridge.patch_path() {
+        eprintln!("patch applied: {}", path.display());
+    }
+    let mut emulator = Emulator::new(cartridge, options.mode, 44100);
+    if let Some(path) = &options.trace {
+        if let Err(e) = emulator.start_trace(path) {
+            eprintln!("failed to open trace file: {}: {}", path.display(), e);
+            std::process::exit(2);
+        }
+    }
+
+    let mut met = false;
+    let mut frames = 0;
+    while frames < options.frames {
+        emulator.run_frame();
+        // 音は使わないので捨てる
+        emulator.audio_samples();
+        frames += 1;
+
+        for event in emulator.take_events() {
+            match event {
+                EmulatorEvent::Rumble(on) => {
+                    eprintln!("frame {}: rumble {}", frames, if on { "on" } else { "off" })
+                }
+                EmulatorEvent::IllegalOpcode { pc, opcode } => eprintln!(
+                    "frame {}: illegal opcode {:02X} at {:04X}: CPU locked up",
+                    frames, opcode, pc
+                ),
+                EmulatorEvent::TraceFailed(kind) => {
+                    eprintln!("frame {}: failed to write trace: {}", frames, kind)
+                }
+            }
+        }
+
+        if options.has_condition() && condition_met(&mut emulator, &options) {
+            met = true;
+            break;
+        }
+    }
+    eprintln!("ran {} frames", frames);
+    // この後の process::exit では Drop が呼ばれないので、ここで書き出す
+    if let Err(e) = emulator.stop_trace() {
+        eprintln!("failed to write trace: {}", e);
+    }
+
+    if let Some(path) = &options.png {
+        if let Err(message) = write_png(path, emulator.framebuffer()) {
+            eprintln!("failed to write PNG: {}", message);
+            std::process::exit(2);
+        }
+    }
+
+    let serial = emulator.serial_output();
+    match &options.serial {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, serial) {
+                eprintln!("failed to write serial output: {}: {}", path.display(), e);
+                std::process::exit(2);
+            }
+        }
+        None => {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(serial).unwrap();
+            stdout.flush().unwrap();
+        }
+    }
+
+    if options.has_condition() && !met {
+        eprintln!("stop condition was not met");
+        std::process::exit(1);
+    }
+    if options.until_mooneye && contains(serial, &MOONEYE_FAIL) {
+        eprintln!("mooneye test failed");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,177 @@
+// ROMのヘッダー情報を表示する
+//
+//   gb-info rom/tetris.gb
+//   gb-info rom/            (ディレクトリ内の .gb / .gbc をすべて)
+
+use std::path::{Path, PathBuf};
+
+use gameboy_emulator::cartridge::{CartridgeHeader, CgbSupport};
+use gameboy_emulator::mapper::MapperRegistry;
+
+const USAGE: &str = "usage: gb-info <ROM or DIRECTORY>...
+
+print the cartridge header of each ROM. for a directory, every .gb/.gbc file in it is shown.
+
+exit status is 0 when every file was read and its header checksum is valid, 1 otherwise.";
+
+fn is_rom(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("gb") || ext.eq_ignore_ascii_case("gbc"),
+        None => false,
+    }
+}
+
+fn collect(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let entries = std::fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && is_rom(path))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn size(bytes: usize) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{} MiB", bytes / 1024 / 1024)
+    } else {
+        format!("{} KiB", bytes / 1024)
+    }
+}
+
+fn result(ok: bool) -> &'static str {
+    if ok {
+        "OK"
+    } else {
+        "NG"
+    }
+}
+
+fn print(header: &CartridgeHeader, registry: &MapperRegistry) {
+    println!("  title:           {}", header.title);
+    if let Some(code) = &header.manufacturer_code {
+        println!("  manufacturer:    {}", code);
+    }
+    let cgb = match header.cgb_support() {
+        CgbSupport::None => "no",
+        CgbSupport::Enhanced => "yes",
+        CgbSupport::Only => "only",
+    };
+    println!("  CGB:             {} ({:02X})", cgb, header.cgb_flag);
+    println!(
+        "  SGB:             {} ({:02X})",
+        if header.supports_sgb() { "yes" } else { "no" },
+        header.sgb_flag
+    );
+    println!(
+        "  cartridge type:  {} ({:02X}){}",
+        header.cartridge_type_name().unwrap_or("unknown"),
+        header.cartridge_type,
+        if registry.is_supported(header.cartridge_type) {
+            ""
+        } else {
+            " [unsupported]"
+        }
+    );
+    println!(
+        "  ROM size:        {} ({:02X})",
+        header.rom_size_bytes().map_or("unknown".to_string(), size),
+        header.rom_size
+    );
+    println!(
+        "  RAM size:        {} ({:02X})",
+        header.ram_size_bytes().map_or("unknown".to_string(), size),
+        header.ram_size
+    );
+    println!(
+        "  destination:     {} ({:02X})",
+        if header.is_japanese() {
+            "Japan"
+        } else {
+            "overseas"
+        },
+        header.destination
+    );
+    // 旧ライセンシーコードが0x33のときは新ライセンシーコード
+    println!(
+        "  licensee:        {}{}",
+        header.licensee_code(),
+        if header.old_licensee_code == 0x33 {
+            " (new)"
+        } else {
+            " (old)"
+        }
+    );
+    println!("  version:         {}", header.version);
+    println!(
+        "  header checksum: {:02X} {} (computed: {:02X})",
+        header.header_checksum,
+        result(header.header_checksum_ok()),
+        header.computed_header_checksum
+    );
+    println!(
+        "  global checksum: {:04X} {} (computed: {:04X})",
+        header.global_checksum,
+        result(header.global_checksum_ok()),
+        header.computed_global_checksum
+    );
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    if args.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+
+    let registry = MapperRegistry::default();
+    let mut ok = true;
+    let mut first = true;
+    for arg in &args {
+        let files = match collect(Path::new(arg)) {
+            Ok(files) => files,
+            Err(message) => {
+                eprintln!("{}", message);
+                ok = false;
+                continue;
+            }
+        };
+        for file in files {
+            if !first {
+                println!();
+            }
+            first = false;
+            println!("{}", file.display());
+
+            let rom = match std::fs::read(&file) {
+                Ok(rom) => rom,
+                Err(e) => {
+                    eprintln!("{}: {}", file.display(), e);
+                    ok = false;
+                    continue;
+                }
+            };
+            match CartridgeHeader::parse(&rom) {
+                Some(header) => {
+                    print(&header, &registry);
+                    ok &= header.header_checksum_ok();
+                }
+                None => {
+                    eprintln!("{}: file is too small for a ROM", file.display());
+                    ok = false;
+                }
+            }
+        }
+    }
+
+    if !ok {
+        std::process::exit(1);
+    }
+}
@@ -1,86 +1,225 @@
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::Read;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
-use crate::mapper::mbc1::MBC1;
-use crate::mapper::mbc3::MBC3;
-use crate::mapper::mbc5::MBC5;
-use crate::mapper::nombc::NoMBC;
-use crate::mapper::Mapper;
+mod archive;
+pub mod header;
+pub mod patch;
+
+pub use header::{CartridgeHeader, CgbSupport};
+
+use crate::cheat::GameGenie;
+use crate::mapper::{MapperImpl, MapperRegistry};
+use crate::savestate::{RomId, SaveStateError, StateReader, StateWriter};
+use patch::PatchError;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    // ヘッダー (0x0150 byte) より小さい
+    TooSmall(usize),
+    UnsupportedCartridgeType(u8),
+    UnsupportedRamSize(u8),
+    // zip が壊れているなど
+    Archive {
+        path: PathBuf,
+        message: String,
+    },
+    // zip の中に .gb / .gbc (entry を指定した場合はそのファイル) がない
+    RomNotFoundInArchive {
+        path: PathBuf,
+        entry: Option<String>,
+    },
+    // パッチが壊れている、別のROM用など
+    Patch {
+        path: PathBuf,
+        error: PatchError,
+    },
+    // セーブファイルの大きさが合わない。LoadOptions::save_mismatch で扱いを変えられる
+    SaveSizeMismatch {
+        path: PathBuf,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            CartridgeError::TooSmall(size) => {
+                write!(f, "ROM file is too small ({} bytes)", size)
+            }
+            CartridgeError::UnsupportedCartridgeType(value) => {
+                match header::cartridge_type_name(*value) {
+                    Some(name) => write!(f, "unsupported cartridge type: {} ({:02X})", name, value),
+                    None => write!(f, "unsupported cartridge type: {:02X}", value),
+                }
+            }
+            CartridgeError::UnsupportedRamSize(value) => {
+                write!(f, "unsupported ram size: {:02X}", value)
+            }
+            CartridgeError::Archive { path, message } => {
+                write!(f, "{}: {}", path.display(), message)
+            }
+            CartridgeError::RomNotFoundInArchive { path, entry } => match entry {
+                Some(entry) => write!(f, "{}: {} not found in the archive", path.display(), entry),
+                None => write!(f, "{}: no .gb/.gbc file in the archive", path.display()),
+            },
+            CartridgeError::Patch { path, error } => write!(f, "{}: {}", path.display(), error),
+            CartridgeError::SaveSizeMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "save file size is not match: {} (expected: {} bytes, found: {} bytes)",
+                path.display(),
+                expected,
+                found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartridgeError::Io { error, .. } => Some(error),
+            CartridgeError::Patch { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// セーブファイルの大きさが合わない場合にどうするか
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum SaveMismatch {
+    // CartridgeError::SaveSizeMismatch を返す
+    #[default]
+    Fail,
+    // 先頭から読めるだけ読む。次にセーブした時に正しい大きさになる
+    Resize,
+    // セーブファイルを .bak にリネームして、空のRAMで始める
+    Backup,
+}
+
+// どのパッチ (.ips / .ups / .bps) を当てるか
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum PatchFile {
+    // ROMと同じ名前のパッチがあれば当てる
+    #[default]
+    Auto,
+    Path(PathBuf),
+    Off,
+}
+
+#[derive(Clone, Default)]
+pub struct LoadOptions {
+    // セーブファイルを置くディレクトリ。None ならROMと同じ場所
+    pub save_dir: Option<PathBuf>,
+    // zip から読み込むファイルの名前。None なら最初の .gb / .gbc
+    pub archive_entry: Option<String>,
+    pub patch: PatchFile,
+    pub registry: MapperRegistry,
+    pub save_mismatch: SaveMismatch,
+}
 
 pub struct Cartridge {
     rom: Vec<u8>,
     ram: Vec<u8>,
-    mapper: Mapper,
+    header: CartridgeHeader,
+    mapper: Box<dyn MapperImpl>,
     pub palette: [[u16; 4]; 3], // [BGP, OBJ0, OBJ1]
 
-    ram_file_path: String,
+    // None の場合はセーブしない
+    ram_file_path: Option<PathBuf>,
+    patch_path: Option<PathBuf>,
+    // 最後にセーブしてから外部RAM (0xA000-0xBFFF) に書き込んだか
+    dirty: bool,
+    game_genie: Vec<GameGenie>,
 }
 
 impl Cartridge {
-    pub fn new(filename: &str) -> Self {
-        let mut f = File::open(&filename).expect("no file found");
-        let metadata = fs::metadata(&filename).expect("unable to read metadata");
-        let mut rom = vec![0; metadata.len() as usize];
-        f.read(&mut rom).expect("buffer overflow");
-
-        // raw[0x0143] // CGB flag
-        // 0x0146 — SGB flag
-
-        // 0x0147 — Cartridge type ==> mapper
-        // 0148 — ROM size
-        // 0149 — RAM size
-
-        // MBC1 の対応が必要。
-
-        println!("{:02X?}", &rom[0x0147..=0x0149]);
-
-        let mapper = match rom[0x0147] {
-            0x00 => Mapper::NoMBC(NoMBC::new()),
-            0x01 => Mapper::MBC1(MBC1::new()),
-            0x02 => Mapper::MBC1(MBC1::new()), // + RAM
-            0x03 => Mapper::MBC1(MBC1::new()), // + RAM + BATTERY
-            0x19 => Mapper::MBC5(MBC5::new()),
-            0x1B => Mapper::MBC5(MBC5::new()), // + RAM + BATTERY
-            0x10 => Mapper::MBC3(MBC3::new()), // TIMER + RAM + BATTERY
-            _ => panic!("unsupported cartridge type."),
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CartridgeError> {
+        Self::load_with(path, &LoadOptions::default())
+    }
+
+    // .zip と .gz (gzip) はそのまま読み込める。
+    // セーブファイルはアーカイブと同じ場所に、中のROMの名前で作る。
+    // パッチを当てた場合は、パッチごとに別のセーブファイルにする。
+    pub fn load_with<P: AsRef<Path>>(
+        path: P,
+        options: &LoadOptions,
+    ) -> Result<Self, CartridgeError> {
+        let path = path.as_ref();
+        let (mut rom, rom_name) = archive::read_rom(path, options.archive_entry.as_deref())?;
+
+        let patch_path = match &options.patch {
+            PatchFile::Auto => find_patch(path, &rom_name),
+            PatchFile::Path(patch_path) => Some(patch_path.clone()),
+            PatchFile::Off => None,
         };
-        // 19 => bm MBC5 0K
-        // 10 => gold MBC3+TIMER+RAM+BATTERY
-        // 1B => yugi3 MBC5+RAM+BATTERY OK
-        // 1B => yugi4 MBC5+RAM+BATTERY OK
+        if let Some(patch_path) = &patch_path {
+            let data = fs::read(patch_path).map_err(|error| CartridgeError::Io {
+                path: patch_path.clone(),
+                error,
+            })?;
+            rom = patch::apply(&rom, &data).map_err(|error| CartridgeError::Patch {
+                path: patch_path.clone(),
+                error,
+            })?;
+        }
 
-        let ram_size = match rom[0x0149] {
-            0x00 => 0,
-            0x01 => 0,
-            0x02 => 8 * 1024,
-            0x03 => 32 * 1024,
-            0x04 => 128 * 1024,
-            0x05 => 64 * 1024,
-            _ => panic!("unsupported ram size."),
+        let save_name = save_file_name(&rom_name, patch_path.as_deref());
+        let save_path = match &options.save_dir {
+            Some(dir) => dir.join(save_name),
+            None => path.with_file_name(save_name),
         };
+        let mut cartridge = Self::build(rom, Some(save_path), options)?;
+        cartridge.patch_path = patch_path;
+        Ok(cartridge)
+    }
 
-        // let mut ram =
+    // ファイルを使わずにROMのデータから作る。セーブファイルは読み書きしない
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        Self::build(rom, None, &LoadOptions::default())
+    }
 
-        let mut path = PathBuf::from(filename);
-        path.set_extension("save");
-        let ram_file_path = path.to_str().unwrap();
+    fn build(
+        rom: Vec<u8>,
+        ram_file_path: Option<PathBuf>,
+        options: &LoadOptions,
+    ) -> Result<Self, CartridgeError> {
+        let header = CartridgeHeader::parse(&rom).ok_or(CartridgeError::TooSmall(rom.len()))?;
 
-        let ram = if path.is_file() {
-            let mut f = File::open(&ram_file_path).expect("no save file found");
-            let metadata = fs::metadata(&ram_file_path).expect("unable to read metadata");
-            if metadata.len() != ram_size {
-                panic!("save file size is not match.");
-            }
+        let mapper =
+            options
+                .registry
+                .create(&rom)
+                .ok_or(CartridgeError::UnsupportedCartridgeType(
+                    header.cartridge_type,
+                ))?;
+        // 19 => bm MBC5 0K
+        // 10 => gold MBC3+TIMER+RAM+BATTERY
+        // 1B => yugi3 MBC5+RAM+BATTERY OK
+        // 1B => yugi4 MBC5+RAM+BATTERY OK
 
-            let mut ram = vec![0; metadata.len() as usize];
-            f.read(&mut ram).expect("buffer overflow");
-            ram
-        } else {
-            vec![0; ram_size as usize]
+        // MBC2 は0x0149に関係なく、内蔵RAMを持っている
+        let ram_size = match mapper.internal_ram_size() {
+            Some(size) => size,
+            None => header
+                .ram_size_bytes()
+                .ok_or(CartridgeError::UnsupportedRamSize(header.ram_size))?,
         };
 
+        let ram = vec![0; ram_size];
+
         let checksum_table: [u8; 65 + 14] = [
             0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9,
             0x3E, 0x70, 0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34,
@@ -164,22 +303,8 @@ impl Cartridge {
         ];
 
         // 互換パレット
-        let mut made_nintendo = false;
-        let licensee_code = rom[0x014B];
-        if licensee_code == 0x33 {
-            let new_licensee_code1 = rom[0x0144];
-            let new_licensee_code2 = rom[0x0145];
-            /* equal '01' */
-            if new_licensee_code1 == 0x30 && new_licensee_code2 == 0x31 {
-                made_nintendo = true;
-            }
-        } else {
-            if licensee_code == 0x01 {
-                made_nintendo = true;
-            }
-        }
-
-        let cgb_game = (rom[0x0143] & 0x80) != 0;
+        let made_nintendo = header.is_nintendo();
+        let cgb_game = header.cgb_support() != CgbSupport::None;
         let default_palette_id = 0;
 
         let match_index = if !made_nintendo || cgb_game {
@@ -250,38 +375,533 @@ impl Cartridge {
             palette_table[(palette_comb[2] + 3) as usize],
         ];
 
-        Cartridge {
+        let mut cartridge = Cartridge {
             rom,
             ram,
+            header,
             mapper,
             palette: [bgp, obj0, obj1],
-            ram_file_path: ram_file_path.to_string(),
+            ram_file_path,
+            patch_path: None,
+            dirty: false,
+            game_genie: Vec::new(),
+        };
+        if cartridge.has_battery() {
+            // 以前の形式 (<ROM名>.save) のセーブファイルも読む
+            let source = cartridge.ram_file_path.as_ref().and_then(|path| {
+                [path.clone(), path.with_extension("save")]
+                    .into_iter()
+                    .find(|path| path.is_file())
+            });
+            if let Some(path) = source {
+                cartridge.read_save_file(&path, options.save_mismatch)?;
+            }
+        }
+        Ok(cartridge)
+    }
+
+    fn read_save_file(
+        &mut self,
+        path: &Path,
+        save_mismatch: SaveMismatch,
+    ) -> Result<(), CartridgeError> {
+        let data = fs::read(path).map_err(|error| CartridgeError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        if self.mapper.import_battery(&mut self.ram, &data) {
+            return Ok(());
         }
+        // RAMサイズ 0x01 は以前 RAM なし (0 byte) として扱っていたので、その頃のセーブファイルは RAM が空。
+        // 大きさが合わなくても、RAM を 0 で埋めたまま読み込む
+        if self.header.ram_size == 0x01 && self.mapper.import_battery(&mut [], &data) {
+            return Ok(());
+        }
+        match save_mismatch {
+            SaveMismatch::Fail => {
+                return Err(CartridgeError::SaveSizeMismatch {
+                    path: path.to_path_buf(),
+                    expected: self.mapper.export_battery(&self.ram).len(),
+                    found: data.len(),
+                })
+            }
+            SaveMismatch::Resize => {
+                let size = self.ram.len().min(data.len());
+                self.ram[..size].copy_from_slice(&data[..size]);
+            }
+            SaveMismatch::Backup => {
+                let backup = backup_path(path);
+                fs::rename(path, &backup).map_err(|error| CartridgeError::Io {
+                    path: backup,
+                    error,
+                })?;
+            }
+        }
+        Ok(())
     }
 
     pub fn read_byte(&mut self, addr: u16) -> u8 {
-        return self.mapper.read_byte(&self.rom, &self.ram, addr);
+        let value = self.mapper.read_byte(&self.rom, &self.ram, addr);
+        if addr < 0x8000 {
+            for code in &self.game_genie {
+                if code.address == addr && code.compare.map_or(true, |compare| compare == value) {
+                    return code.value;
+                }
+            }
+        }
+        value
+    }
+
+    // ROMの読み込みを書き換える Game Genie のコード
+    pub fn set_game_genie(&mut self, codes: Vec<GameGenie>) {
+        self.game_genie = codes;
     }
 
     pub fn write_byte(&mut self, addr: u16, value: u8) {
-        return self
+        // RAM が無効な場合などは、セーブファイルを書き直さない
+        if self
             .mapper
-            .write_byte(&mut self.rom, &mut self.ram, addr, value);
+            .write_byte(&mut self.rom, &mut self.ram, addr, value)
+        {
+            self.dirty = true;
+        }
+    }
+
+    // 電池のないカートリッジや、from_bytes で作った場合は何もしない
+    pub fn save_ram(&mut self) -> io::Result<()> {
+        let path = match &self.ram_file_path {
+            Some(path) if self.has_battery() => path.clone(),
+            _ => {
+                self.dirty = false;
+                return Ok(());
+            }
+        };
+        self.export_sav(&path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.header.has_battery()
+    }
+
+    // 他のエミュレーターの .sav を読み込む。次の save_ram で自分のセーブファイルに書く
+    pub fn import_sav(&mut self, path: &Path) -> Result<(), CartridgeError> {
+        self.read_save_file(path, SaveMismatch::Fail)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    // RAMの内容 (MBC3 と HuC3 は末尾にRTC) を .sav として書き出す
+    pub fn export_sav(&mut self, path: &Path) -> io::Result<()> {
+        write_atomic(path, &self.mapper.export_battery(&self.ram))
+    }
+
+    pub fn tick_frame(&mut self) {
+        self.mapper.tick_frame();
+    }
+
+    // 振動カートリッジの場合、前回呼んでからモーターが回ったか
+    pub fn take_rumble(&mut self) -> Option<bool> {
+        self.mapper.take_rumble()
+    }
+
+    // 外部RAM (全バンク)
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    // from_bytes で作った場合は None
+    pub fn save_path(&self) -> Option<&Path> {
+        self.ram_file_path.as_deref()
+    }
+
+    // 当てたパッチ
+    pub fn patch_path(&self) -> Option<&Path> {
+        self.patch_path.as_deref()
     }
 
-    pub fn save_ram(&mut self) {
-        let mut file = File::create(self.ram_file_path.as_str()).unwrap();
-        file.write_all(&self.ram).unwrap();
-        file.flush().unwrap();
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
     }
 
+    // ステートセーブでROMを識別するための情報
+    pub fn rom_id(&self) -> RomId {
+        RomId::from_rom(&self.rom)
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_vec(&self.ram);
+        self.mapper.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        // セーブファイルはゲームが次に RAM に書き込むまで書き換えない。
+        // 巻き戻しやステートロードでゲーム内でのセーブが消えないようにする
+        r.read_vec(&mut self.ram)?;
+        self.mapper.load_state(r)
+    }
+
+    #[cfg(test)]
     pub fn for_test() -> Self {
         Cartridge {
             rom: vec![0; 0x8000 as usize],
             ram: vec![0; 0x0000 as usize],
-            mapper: Mapper::NoMBC(NoMBC::new()),
+            header: CartridgeHeader::parse(&[0; 0x8000]).unwrap(),
+            mapper: Box::new(Flat {}),
             palette: [[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
-            ram_file_path: "_.save".to_string(),
+            ram_file_path: None,
+            patch_path: None,
+            dirty: false,
+            game_genie: Vec::new(),
         }
     }
 }
+
+// テストごとに別のディレクトリを使う
+#[cfg(test)]
+pub(crate) fn tempDir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "gameboy_emulator_test_{}_{}",
+        name,
+        std::process::id()
+    ));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// テスト用。ROM領域にも書き込めるようにして、命令を直接置けるようにする。
+#[cfg(test)]
+struct Flat {}
+
+#[cfg(test)]
+impl MapperImpl for Flat {
+    fn read_byte(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => rom[addr as usize],
+            0xA000..=0xBFFF => ram[addr as usize - 0xA000],
+            _ => panic!("should not reach!"),
+        }
+    }
+
+    fn write_byte(&mut self, rom: &mut [u8], ram: &mut [u8], addr: u16, value: u8) -> bool {
+        match addr {
+            0x0000..=0x7FFF => rom[addr as usize] = value,
+            0xA000..=0xBFFF => {
+                ram[addr as usize - 0xA000] = value;
+                return true;
+            }
+            _ => panic!("should not reach!"),
+        }
+        false
+    }
+}
+
+// ROM (アーカイブの場合は、アーカイブか中のROM) と同じ名前のパッチを探す
+fn find_patch(path: &Path, rom_name: &Path) -> Option<PathBuf> {
+    let names = [path.file_name()?, rom_name.as_os_str()];
+    for name in names {
+        for ext in ["ips", "ups", "bps"] {
+            let patch_path = path.with_file_name(name).with_extension(ext);
+            if patch_path.is_file() {
+                return Some(patch_path);
+            }
+        }
+    }
+    None
+}
+
+// "game.gb" -> "game.sav"
+// パッチを当てた場合
+//   game.gb + game.ips    -> "game.ips.sav"
+//   game.gb + english.bps -> "game.english.bps.sav"
+fn save_file_name(rom_name: &Path, patch_path: Option<&Path>) -> PathBuf {
+    let stem = rom_name.file_stem().unwrap_or_default();
+    let mut name = OsString::from(stem);
+    if let Some(patch_path) = patch_path {
+        name.push(".");
+        if patch_path.file_stem() == Some(stem) {
+            name.push(patch_path.extension().unwrap_or_default());
+        } else {
+            name.push(patch_path.file_name().unwrap_or_default());
+        }
+    }
+    name.push(".sav");
+    PathBuf::from(name)
+}
+
+// 一時ファイルに書いてからリネームする。書いている途中で落ちても元のファイルは残る
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)
+}
+
+// 使われていない "<セーブファイル>.bak", "<セーブファイル>.bak1", ... を返す
+fn backup_path(path: &Path) -> PathBuf {
+    let mut i = 0;
+    loop {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".bak");
+        if i > 0 {
+            name.push(i.to_string());
+        }
+        let backup = PathBuf::from(name);
+        if !backup.exists() {
+            return backup;
+        }
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // MBC1+RAM+BATTERY, 8KB RAM
+    fn newRom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        rom
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        assert!(Cartridge::from_bytes(newRom()).is_ok());
+        assert!(matches!(
+            Cartridge::from_bytes(vec![0; 0x100]),
+            Err(CartridgeError::TooSmall(0x100))
+        ));
+
+        let mut rom = newRom();
+        rom[0x0147] = 0x22;
+        assert!(matches!(
+            Cartridge::from_bytes(rom),
+            Err(CartridgeError::UnsupportedCartridgeType(0x22))
+        ));
+
+        let mut rom = newRom();
+        rom[0x0149] = 0x07;
+        assert!(matches!(
+            Cartridge::from_bytes(rom),
+            Err(CartridgeError::UnsupportedRamSize(0x07))
+        ));
+    }
+
+    #[test]
+    fn test_load_not_found() {
+        let dir = tempDir("not_found");
+        assert!(matches!(
+            Cartridge::load(dir.join("none.gb")),
+            Err(CartridgeError::Io { .. })
+        ));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_save_mismatch() {
+        let dir = tempDir("save_mismatch");
+        let rom_path = dir.join("game.gb");
+        let save_path = dir.join("game.save");
+        fs::write(&rom_path, newRom()).unwrap();
+        fs::write(&save_path, vec![0x12; 0x1000]).unwrap();
+
+        assert!(matches!(
+            Cartridge::load(&rom_path),
+            Err(CartridgeError::SaveSizeMismatch {
+                expected: 0x2000,
+                found: 0x1000,
+                ..
+            })
+        ));
+
+        let mut options = LoadOptions {
+            save_mismatch: SaveMismatch::Resize,
+            ..LoadOptions::default()
+        };
+        let cartridge = Cartridge::load_with(&rom_path, &options).unwrap();
+        assert_eq!(cartridge.ram.len(), 0x2000);
+        assert_eq!(cartridge.ram[0x0FFF], 0x12);
+        assert_eq!(cartridge.ram[0x1000], 0x00);
+
+        options.save_mismatch = SaveMismatch::Backup;
+        let cartridge = Cartridge::load_with(&rom_path, &options).unwrap();
+        assert_eq!(cartridge.ram[0x0000], 0x00);
+        assert!(!save_path.exists());
+        assert_eq!(fs::read(dir.join("game.save.bak")).unwrap().len(), 0x1000);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_legacy_ram_size_01() {
+        // 2KB RAM。以前は RAM なしとして空のセーブファイルを書いていた
+        let dir = tempDir("legacy_ram_size_01");
+        let rom_path = dir.join("game.gb");
+        let mut rom = newRom();
+        rom[0x0149] = 0x01;
+        fs::write(&rom_path, rom).unwrap();
+        fs::write(dir.join("game.sav"), []).unwrap();
+
+        let cartridge = Cartridge::load(&rom_path).unwrap();
+        assert_eq!(cartridge.ram.len(), 0x800);
+        assert!(cartridge.ram.iter().all(|&b| b == 0));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_patch() {
+        let dir = tempDir("patch");
+        let rom_path = dir.join("game.gb");
+        fs::write(&rom_path, newRom()).unwrap();
+        // 0x0134 に "EN" を書くIPS
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0x00, 0x01, 0x34, 0x00, 0x02, b'E', b'N']);
+        ips.extend_from_slice(b"EOF");
+        fs::write(dir.join("game.ips"), &ips).unwrap();
+        fs::write(dir.join("english.ips"), &ips).unwrap();
+
+        let cartridge = Cartridge::load(&rom_path).unwrap();
+        assert_eq!(cartridge.header().title, "EN");
+        assert_eq!(cartridge.patch_path(), Some(dir.join("game.ips").as_path()));
+        assert_eq!(
+            cartridge.save_path(),
+            Some(dir.join("game.ips.sav").as_path())
+        );
+
+        let mut options = LoadOptions {
+            patch: PatchFile::Path(dir.join("english.ips")),
+            ..LoadOptions::default()
+        };
+        let cartridge = Cartridge::load_with(&rom_path, &options).unwrap();
+        assert_eq!(cartridge.header().title, "EN");
+        assert_eq!(
+            cartridge.save_path(),
+            Some(dir.join("game.english.ips.sav").as_path())
+        );
+
+        options.patch = PatchFile::Off;
+        let cartridge = Cartridge::load_with(&rom_path, &options).unwrap();
+        assert_eq!(cartridge.header().title, "");
+        assert_eq!(cartridge.patch_path(), None);
+        assert_eq!(cartridge.save_path(), Some(dir.join("game.sav").as_path()));
+
+        fs::write(dir.join("game.ips"), b"PATCH").unwrap();
+        assert!(matches!(
+            Cartridge::load(&rom_path),
+            Err(CartridgeError::Patch { .. })
+        ));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_save_ram() {
+        let dir = tempDir("save_ram");
+        let rom_path = dir.join("game.gb");
+        let save_path = dir.join("game.sav");
+        fs::write(&rom_path, newRom()).unwrap();
+
+        let mut cartridge = Cartridge::load(&rom_path).unwrap();
+        assert!(!cartridge.is_dirty());
+        // RAM が無効な間の書き込みでは書き直さない
+        cartridge.write_byte(0xA000, 0x12);
+        assert!(!cartridge.is_dirty());
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0xA000, 0x12);
+        assert!(cartridge.is_dirty());
+        cartridge.save_ram().unwrap();
+        assert!(!cartridge.is_dirty());
+        assert_eq!(fs::read(&save_path).unwrap()[0], 0x12);
+        assert!(!dir.join("game.sav.tmp").exists());
+
+        // 他のエミュレーターの .sav
+        let other = dir.join("other.sav");
+        fs::write(&other, vec![0x34; 0x2000]).unwrap();
+        cartridge.import_sav(&other).unwrap();
+        assert_eq!(cartridge.read_byte(0xA000), 0x34);
+        assert!(cartridge.is_dirty());
+        cartridge.export_sav(&dir.join("export.sav")).unwrap();
+        assert_eq!(
+            fs::read(dir.join("export.sav")).unwrap(),
+            vec![0x34; 0x2000]
+        );
+        assert!(cartridge.import_sav(&rom_path).is_err());
+
+        // 電池がない場合はセーブしない
+        let mut rom = newRom();
+        rom[0x0147] = 0x02;
+        fs::write(&rom_path, rom).unwrap();
+        fs::remove_file(&save_path).unwrap();
+        let mut cartridge = Cartridge::load(&rom_path).unwrap();
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0xA000, 0x12);
+        cartridge.save_ram().unwrap();
+        assert!(!cartridge.is_dirty());
+        assert!(!save_path.exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_load_state_keeps_save() {
+        let dir = tempDir("load_state");
+        let rom_path = dir.join("game.gb");
+        fs::write(&rom_path, newRom()).unwrap();
+
+        let mut cartridge = Cartridge::load(&rom_path).unwrap();
+        let mut w = StateWriter::new(&cartridge.rom_id());
+        cartridge.save_state(&mut w);
+        let state = w.into_bytes();
+
+        // ステートを作った後にゲーム内でセーブした
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0xA000, 0x12);
+        cartridge.save_ram().unwrap();
+
+        let mut r = StateReader::new(&state, &cartridge.rom_id()).unwrap();
+        cartridge.load_state(&mut r).unwrap();
+        assert_eq!(cartridge.ram()[0], 0x00);
+        assert!(!cartridge.is_dirty());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_game_genie() {
+        let mut rom = newRom();
+        rom[0x4A17] = 0xC8;
+        rom[0x4A18] = 0x12;
+        let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+        cartridge.set_game_genie(vec![
+            GameGenie {
+                address: 0x4A17,
+                value: 0x00,
+                compare: Some(0xC8),
+            },
+            // 比較する値が違うので書き換えない
+            GameGenie {
+                address: 0x4A18,
+                value: 0x00,
+                compare: Some(0x34),
+            },
+        ]);
+        assert_eq!(cartridge.read_byte(0x4A17), 0x00);
+        assert_eq!(cartridge.read_byte(0x4A18), 0x12);
+
+        cartridge.set_game_genie(Vec::new());
+        assert_eq!(cartridge.read_byte(0x4A17), 0xC8);
+    }
+}
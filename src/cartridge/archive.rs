@@ -0,0 +1,217 @@
+// 圧縮されたROMの読み込み
+//
+// 拡張子ではなく先頭のバイトで判定する。
+//   zip:  "PK\x03\x04"。最初の .gb / .gbc か、名前を指定したファイルを読む
+//   gzip: 0x1F 0x8B
+
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use super::CartridgeError;
+
+const ZIP_MAGIC: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+// 展開後 / パッチ後の大きさの上限。一番大きいROMは 8MB
+pub(super) const MAX_ROM_SIZE: usize = 16 * 1024 * 1024;
+
+// ROMのデータと、セーブファイルの名前に使うROMのファイル名を返す
+pub fn read_rom(path: &Path, entry: Option<&str>) -> Result<(Vec<u8>, PathBuf), CartridgeError> {
+    let io_error = |error| CartridgeError::Io {
+        path: path.to_path_buf(),
+        error,
+    };
+    let data = std::fs::read(path).map_err(io_error)?;
+    let file_name = PathBuf::from(path.file_name().unwrap_or_default());
+
+    if data.starts_with(&ZIP_MAGIC) {
+        read_zip(path, data, entry)
+    } else if data.starts_with(&GZIP_MAGIC) {
+        let rom = read_limited(GzDecoder::new(data.as_slice())).map_err(io_error)?;
+        // "game.gb.gz" -> "game.gb"
+        let name = match file_name.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("gz") => file_name.with_extension(""),
+            _ => file_name,
+        };
+        Ok((rom, name))
+    } else {
+        Ok((data, file_name))
+    }
+}
+
+fn read_zip(
+    path: &Path,
+    data: Vec<u8>,
+    entry: Option<&str>,
+) -> Result<(Vec<u8>, PathBuf), CartridgeError> {
+    let archive_error = |error: zip::result::ZipError| CartridgeError::Archive {
+        path: path.to_path_buf(),
+        message: error.to_string(),
+    };
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(archive_error)?;
+
+    let names: Vec<String> = (0..archive.len())
+        .filter_map(|i| {
+            let file = archive.by_index(i).ok()?;
+            if file.is_file() {
+                Some(file.name().to_string())
+            } else {
+                None
+            }
+        })
+        .collect();
+    let name = names
+        .into_iter()
+        .find(|name| match entry {
+            // フルパスでもファイル名だけでもよい
+            Some(entry) => name == entry || base_name(name) == entry,
+            None => is_rom_name(name),
+        })
+        .ok_or_else(|| CartridgeError::RomNotFoundInArchive {
+            path: path.to_path_buf(),
+            entry: entry.map(|entry| entry.to_string()),
+        })?;
+
+    let file = archive.by_name(&name).map_err(archive_error)?;
+    let rom = read_limited(file).map_err(|error| CartridgeError::Io {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    Ok((rom, PathBuf::from(base_name(&name))))
+}
+
+fn read_limited<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader
+        .take(MAX_ROM_SIZE as u64 + 1)
+        .read_to_end(&mut data)?;
+    if data.len() > MAX_ROM_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "ROM in the archive is too large",
+        ));
+    }
+    Ok(data)
+}
+
+// zip の中のパスは '/' 区切り
+fn base_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("gb") || ext.eq_ignore_ascii_case("gbc"),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+    use crate::cartridge::{tempDir, Cartridge, LoadOptions};
+
+    fn newZip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_zip() {
+        let dir = tempDir("zip");
+        let path = dir.join("games.zip");
+        std::fs::write(
+            &path,
+            newZip(&[
+                ("readme.txt", b"hello"),
+                ("roms/first.gb", &[1; 0x200]),
+                ("roms/second.gbc", &[2; 0x200]),
+            ]),
+        )
+        .unwrap();
+
+        let (rom, name) = read_rom(&path, None).unwrap();
+        assert_eq!(rom, vec![1; 0x200]);
+        assert_eq!(name, PathBuf::from("first.gb"));
+
+        let (rom, name) = read_rom(&path, Some("second.gbc")).unwrap();
+        assert_eq!(rom, vec![2; 0x200]);
+        assert_eq!(name, PathBuf::from("second.gbc"));
+        assert!(read_rom(&path, Some("roms/second.gbc")).is_ok());
+
+        assert!(matches!(
+            read_rom(&path, Some("third.gb")),
+            Err(CartridgeError::RomNotFoundInArchive { entry: Some(_), .. })
+        ));
+
+        std::fs::write(&path, newZip(&[("readme.txt", b"hello")])).unwrap();
+        assert!(matches!(
+            read_rom(&path, None),
+            Err(CartridgeError::RomNotFoundInArchive { entry: None, .. })
+        ));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_gzip() {
+        let dir = tempDir("gzip");
+        let path = dir.join("game.gb.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&[3; 0x200]).unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let (rom, name) = read_rom(&path, None).unwrap();
+        assert_eq!(rom, vec![3; 0x200]);
+        assert_eq!(name, PathBuf::from("game.gb"));
+
+        // 圧縮されていないファイルはそのまま
+        let path = dir.join("plain.gb");
+        std::fs::write(&path, [4; 0x200]).unwrap();
+        let (rom, name) = read_rom(&path, None).unwrap();
+        assert_eq!(rom, vec![4; 0x200]);
+        assert_eq!(name, PathBuf::from("plain.gb"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_save_path() {
+        let dir = tempDir("archive_save_path");
+        let path = dir.join("games.zip");
+        std::fs::write(&path, newZip(&[("Game Title.gb", &[0; 0x8000])])).unwrap();
+
+        let cartridge = Cartridge::load(&path).unwrap();
+        assert_eq!(
+            cartridge.save_path(),
+            Some(dir.join("Game Title.sav").as_path())
+        );
+
+        let options = LoadOptions {
+            save_dir: Some(PathBuf::from("saves")),
+            ..LoadOptions::default()
+        };
+        let cartridge = Cartridge::load_with(&path, &options).unwrap();
+        assert_eq!(
+            cartridge.save_path(),
+            Some(Path::new("saves/Game Title.sav"))
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
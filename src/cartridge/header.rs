@@ -0,0 +1,327 @@
+// カートリッジヘッダー (0x0100-0x014F) の解析
+//
+//   0x0134-0x0143 タイトル (CGB対応ソフトは 0x013F-0x0142 がメーカーコード, 0x0143 がCGBフラグ)
+//   0x0144-0x0145 新ライセンシーコード
+//   0x0146        SGBフラグ
+//   0x0147        カートリッジタイプ
+//   0x0148        ROMサイズ
+//   0x0149        RAMサイズ
+//   0x014A        仕向地
+//   0x014B        旧ライセンシーコード
+//   0x014C        バージョン
+//   0x014D        ヘッダーチェックサム
+//   0x014E-0x014F グローバルチェックサム (ビッグエンディアン)
+
+use crate::mapper;
+
+pub const HEADER_END: usize = 0x0150;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CgbSupport {
+    None,
+    // DMGでも動く
+    Enhanced,
+    // CGB専用
+    Only,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb_flag: u8,
+    pub new_licensee_code: [u8; 2],
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub destination: u8,
+    pub old_licensee_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub computed_header_checksum: u8,
+    pub global_checksum: u16,
+    pub computed_global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    // ROMがヘッダーの分もない場合は None
+    pub fn parse(rom: &[u8]) -> Option<Self> {
+        if rom.len() < HEADER_END {
+            return None;
+        }
+
+        let cgb_flag = rom[0x0143];
+        let manufacturer = &rom[0x013F..0x0143];
+        // メーカーコードがあるかどうかはヘッダーからは分からないので、
+        // CGB対応で4文字とも英大文字か数字ならメーカーコードとみなす
+        let has_manufacturer = cgb_flag & 0x80 != 0
+            && manufacturer
+                .iter()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        let title_end = if has_manufacturer {
+            0x013F
+        } else if cgb_flag & 0x80 != 0 {
+            0x0143
+        } else {
+            0x0144
+        };
+
+        Some(CartridgeHeader {
+            title: to_text(&rom[0x0134..title_end]),
+            manufacturer_code: if has_manufacturer {
+                Some(to_text(manufacturer))
+            } else {
+                None
+            },
+            cgb_flag,
+            new_licensee_code: [rom[0x0144], rom[0x0145]],
+            sgb_flag: rom[0x0146],
+            cartridge_type: rom[0x0147],
+            rom_size: rom[0x0148],
+            ram_size: rom[0x0149],
+            destination: rom[0x014A],
+            old_licensee_code: rom[0x014B],
+            version: rom[0x014C],
+            header_checksum: rom[0x014D],
+            computed_header_checksum: header_checksum(rom),
+            global_checksum: (rom[0x014E] as u16) << 8 | rom[0x014F] as u16,
+            computed_global_checksum: global_checksum(rom),
+        })
+    }
+
+    pub fn cgb_support(&self) -> CgbSupport {
+        match self.cgb_flag {
+            0xC0 => CgbSupport::Only,
+            v if v & 0x80 != 0 => CgbSupport::Enhanced,
+            _ => CgbSupport::None,
+        }
+    }
+
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03
+    }
+
+    pub fn cartridge_type_name(&self) -> Option<&'static str> {
+        cartridge_type_name(self.cartridge_type)
+    }
+
+    // 電池でRAM (やRTC) を保持するか
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.cartridge_type,
+            0x03 | 0x06
+                | 0x09
+                | 0x0D
+                | 0x0F
+                | 0x10
+                | 0x13
+                | 0x1B
+                | 0x1E
+                | 0x22
+                | 0xFC
+                | 0xFE
+                | 0xFF
+        )
+    }
+
+    // ROMのサイズ (byte)
+    pub fn rom_size_bytes(&self) -> Option<usize> {
+        mapper::rom_banks(self.rom_size).map(|banks| banks * 0x4000)
+    }
+
+    // 外部RAMのサイズ (byte)。MBC2の内蔵RAMは含まない
+    pub fn ram_size_bytes(&self) -> Option<usize> {
+        match self.ram_size {
+            0x00 => Some(0),
+            // 非公式。公式のソフトでは使われていない
+            0x01 => Some(2 * 1024),
+            0x02 => Some(8 * 1024),
+            0x03 => Some(32 * 1024),
+            0x04 => Some(128 * 1024),
+            0x05 => Some(64 * 1024),
+            _ => None,
+        }
+    }
+
+    pub fn is_japanese(&self) -> bool {
+        self.destination == 0x00
+    }
+
+    // 旧ライセンシーコードが0x33の場合は新ライセンシーコード (2文字) を使う
+    pub fn licensee_code(&self) -> String {
+        if self.old_licensee_code == 0x33 {
+            to_text(&self.new_licensee_code)
+        } else {
+            format!("{:02X}", self.old_licensee_code)
+        }
+    }
+
+    pub fn is_nintendo(&self) -> bool {
+        if self.old_licensee_code == 0x33 {
+            &self.new_licensee_code == b"01"
+        } else {
+            self.old_licensee_code == 0x01
+        }
+    }
+
+    pub fn header_checksum_ok(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    pub fn global_checksum_ok(&self) -> bool {
+        self.global_checksum == self.computed_global_checksum
+    }
+}
+
+// カートリッジタイプ (0x0147) の名前
+pub fn cartridge_type_name(value: u8) -> Option<&'static str> {
+    let name = match value {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0B => "MMM01",
+        0x0C => "MMM01+RAM",
+        0x0D => "MMM01+RAM+BATTERY",
+        0x0F => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1A => "MBC5+RAM",
+        0x1B => "MBC5+RAM+BATTERY",
+        0x1C => "MBC5+RUMBLE",
+        0x1D => "MBC5+RUMBLE+RAM",
+        0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xFC => "POCKET CAMERA",
+        0xFD => "BANDAI TAMA5",
+        0xFE => "HuC3",
+        0xFF => "HuC1+RAM+BATTERY",
+        _ => return None,
+    };
+    Some(name)
+}
+
+// 0x0134-0x014C から計算する。実機はこれが合わないと起動しない
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..=0x014C]
+        .iter()
+        .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1))
+}
+
+// グローバルチェックサム以外の全バイトの合計。実機は確認しない
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16))
+}
+
+// 0で終わる文字列。表示できない文字は '?' にする
+fn to_text(bytes: &[u8]) -> String {
+    let text: String = bytes
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| {
+            if c.is_ascii_graphic() || c == b' ' {
+                c as char
+            } else {
+                '?'
+            }
+        })
+        .collect();
+    text.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn newRom(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut rom = newRom(b"POKEMON_GLDAAUJ");
+        rom[0x0143] = 0x80;
+        rom[0x0144..0x0146].copy_from_slice(b"01");
+        rom[0x0146] = 0x03;
+        rom[0x0147] = 0x10;
+        rom[0x0148] = 0x06;
+        rom[0x0149] = 0x03;
+        rom[0x014A] = 0x00;
+        rom[0x014B] = 0x33;
+        rom[0x014C] = 0x02;
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "POKEMON_GLD");
+        assert_eq!(header.manufacturer_code, Some("AAUJ".to_string()));
+        assert_eq!(header.cgb_support(), CgbSupport::Enhanced);
+        assert!(header.supports_sgb());
+        assert_eq!(header.cartridge_type_name(), Some("MBC3+TIMER+RAM+BATTERY"));
+        assert!(header.has_battery());
+        assert_eq!(header.rom_size_bytes(), Some(2 * 1024 * 1024));
+        assert_eq!(header.ram_size_bytes(), Some(32 * 1024));
+        assert!(header.is_japanese());
+        assert_eq!(header.licensee_code(), "01");
+        assert!(header.is_nintendo());
+        assert_eq!(header.version, 2);
+    }
+
+    #[test]
+    fn test_parse_dmg() {
+        let mut rom = newRom(b"TETRIS");
+        rom[0x014A] = 0x01;
+        rom[0x014B] = 0x01;
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.manufacturer_code, None);
+        assert_eq!(header.cgb_support(), CgbSupport::None);
+        assert!(!header.supports_sgb());
+        assert_eq!(header.cartridge_type_name(), Some("ROM ONLY"));
+        assert!(!header.has_battery());
+        assert_eq!(header.rom_size_bytes(), Some(32 * 1024));
+        assert!(!header.is_japanese());
+        assert_eq!(header.licensee_code(), "01");
+        assert!(header.is_nintendo());
+
+        rom[0x0147] = 0x0A;
+        rom[0x0149] = 0x06;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.cartridge_type_name(), None);
+        assert_eq!(header.ram_size_bytes(), None);
+
+        assert_eq!(CartridgeHeader::parse(&rom[..0x014F]), None);
+    }
+
+    #[test]
+    fn test_checksums() {
+        let mut rom = newRom(b"TEST");
+        rom[0x4000] = 0x12;
+        rom[0x014D] = header_checksum(&rom);
+        let sum = global_checksum(&rom);
+        rom[0x014E] = (sum >> 8) as u8;
+        rom[0x014F] = sum as u8;
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(header.header_checksum_ok());
+        assert!(header.global_checksum_ok());
+
+        rom[0x0135] ^= 0xFF;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(!header.header_checksum_ok());
+        assert!(!header.global_checksum_ok());
+    }
+}
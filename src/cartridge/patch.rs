@@ -0,0 +1,448 @@
+// ROMパッチ (IPS / UPS / BPS) の適用
+//
+// 元のファイルは書き換えずに、読み込んだROMのデータに当てる。
+// UPS と BPS は末尾に 元のROM / パッチ後のROM / パッチ自身 の CRC32 を持っているので、それを確認する。
+//
+//   IPS: https://zerosoft.zophar.net/ips.php
+//   UPS, BPS: byuu の仕様 (可変長整数は byuu の形式)
+
+use std::fmt;
+
+use flate2::Crc;
+
+use super::archive::MAX_ROM_SIZE;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// UPS, BPS の末尾の CRC32 x3
+const FOOTER_SIZE: usize = 12;
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    UnexpectedEof,
+    // パッチの中の位置や大きさがおかしい
+    InvalidData,
+    SourceChecksum { expected: u32, found: u32 },
+    TargetChecksum { expected: u32, found: u32 },
+    PatchChecksum { expected: u32, found: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::UnexpectedEof => write!(f, "patch is truncated"),
+            PatchError::InvalidData => write!(f, "patch is broken"),
+            PatchError::SourceChecksum { expected, found } => write!(
+                f,
+                "patch is for another ROM (expected CRC32: {:08X}, found: {:08X})",
+                expected, found
+            ),
+            PatchError::TargetChecksum { expected, found } => write!(
+                f,
+                "patched ROM checksum is not match (expected CRC32: {:08X}, found: {:08X})",
+                expected, found
+            ),
+            PatchError::PatchChecksum { expected, found } => write!(
+                f,
+                "patch checksum is not match (expected CRC32: {:08X}, found: {:08X})",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+// 先頭のバイトで形式を判定して当てる
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], position: usize) -> Self {
+        PatchReader { data, position }
+    }
+
+    fn take(&mut self, size: usize) -> Result<&'a [u8], PatchError> {
+        if self.data.len() - self.position < size {
+            return Err(PatchError::UnexpectedEof);
+        }
+        let value = &self.data[self.position..self.position + size];
+        self.position += size;
+        Ok(value)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, PatchError> {
+        Ok(self.take(1)?[0])
+    }
+
+    // ビッグエンディアン (IPS)
+    fn read_be(&mut self, size: usize) -> Result<usize, PatchError> {
+        Ok(self
+            .take(size)?
+            .iter()
+            .fold(0, |value, &b| value << 8 | b as usize))
+    }
+
+    // 可変長整数 (UPS, BPS)。最上位ビットが立っているバイトで終わる
+    fn read_number(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.read_u8()?;
+            value = (x as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or(PatchError::InvalidData)?;
+            if x & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::InvalidData)?;
+            value = value.checked_add(shift).ok_or(PatchError::InvalidData)?;
+        }
+    }
+}
+
+// UPS, BPS の末尾の CRC32 を確認する
+fn check_footer(source: &[u8], target: &[u8], patch: &[u8]) -> Result<(), PatchError> {
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let value = |i: usize| u32::from_le_bytes(footer[i * 4..i * 4 + 4].try_into().unwrap());
+
+    let found = crc32(&patch[..patch.len() - 4]);
+    if value(2) != found {
+        return Err(PatchError::PatchChecksum {
+            expected: value(2),
+            found,
+        });
+    }
+    let found = crc32(source);
+    if value(0) != found {
+        return Err(PatchError::SourceChecksum {
+            expected: value(0),
+            found,
+        });
+    }
+    let found = crc32(target);
+    if value(1) != found {
+        return Err(PatchError::TargetChecksum {
+            expected: value(1),
+            found,
+        });
+    }
+    Ok(())
+}
+
+//   "PATCH"
+//   [offset (3byte)] [size (2byte)] [data] の繰り返し。size が0の場合は [count (2byte)] [value]
+//   "EOF" [切り詰める大きさ (3byte, 省略可)]
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut target = rom.to_vec();
+    let mut r = PatchReader::new(patch, IPS_MAGIC.len());
+    loop {
+        if r.take(3)? == IPS_EOF {
+            break;
+        }
+        r.position -= 3;
+        let offset = r.read_be(3)?;
+        let size = r.read_be(2)?;
+        let (size, data) = if size == 0 {
+            let count = r.read_be(2)?;
+            (count, None)
+        } else {
+            (size, Some(r.take(size)?))
+        };
+        if offset + size > MAX_ROM_SIZE {
+            return Err(PatchError::InvalidData);
+        }
+        if target.len() < offset + size {
+            target.resize(offset + size, 0);
+        }
+        match data {
+            Some(data) => target[offset..offset + size].copy_from_slice(data),
+            None => {
+                let value = r.read_u8()?;
+                target[offset..offset + size].fill(value);
+            }
+        }
+    }
+    if let Ok(size) = r.read_be(3) {
+        target.truncate(size);
+    }
+    Ok(target)
+}
+
+//   "UPS1" [元の大きさ] [パッチ後の大きさ]
+//   [前の位置からの距離] [XORするデータ... 0] の繰り返し
+//   [CRC32 x3]
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < UPS_MAGIC.len() + FOOTER_SIZE {
+        return Err(PatchError::UnexpectedEof);
+    }
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = PatchReader::new(&patch[..end], UPS_MAGIC.len());
+    let source_size = r.read_number()?;
+    let target_size = r.read_number()?;
+    if target_size > MAX_ROM_SIZE {
+        return Err(PatchError::InvalidData);
+    }
+    if source_size != rom.len() {
+        return Err(PatchError::SourceChecksum {
+            expected: u32::from_le_bytes(patch[end..end + 4].try_into().unwrap()),
+            found: crc32(rom),
+        });
+    }
+
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+    let mut position: usize = 0;
+    while r.position < end {
+        position = position
+            .checked_add(r.read_number()?)
+            .ok_or(PatchError::InvalidData)?;
+        loop {
+            let x = r.read_u8()?;
+            if x == 0 {
+                position += 1;
+                break;
+            }
+            if position < target.len() {
+                target[position] ^= x;
+            }
+            position += 1;
+        }
+    }
+    check_footer(rom, &target, patch)?;
+    Ok(target)
+}
+
+//   "BPS1" [元の大きさ] [パッチ後の大きさ] [メタデータの大きさ] [メタデータ]
+//   [命令] の繰り返し。下位2bitが種類、残りが長さ-1
+//     0: SourceRead  元のROMの同じ位置からコピー
+//     1: TargetRead  パッチの中のデータをコピー
+//     2: SourceCopy  元のROMの (相対位置で指定した) 場所からコピー
+//     3: TargetCopy  パッチ後のROMの (相対位置で指定した) 場所からコピー
+//   [CRC32 x3]
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < BPS_MAGIC.len() + FOOTER_SIZE {
+        return Err(PatchError::UnexpectedEof);
+    }
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = PatchReader::new(&patch[..end], BPS_MAGIC.len());
+    let source_size = r.read_number()?;
+    let target_size = r.read_number()?;
+    if target_size > MAX_ROM_SIZE {
+        return Err(PatchError::InvalidData);
+    }
+    let metadata_size = r.read_number()?;
+    r.take(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceChecksum {
+            expected: u32::from_le_bytes(patch[end..end + 4].try_into().unwrap()),
+            found: crc32(rom),
+        });
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    // 相対位置 (最下位ビットが符号)
+    let relative = |offset: usize, data: usize| {
+        let distance = data >> 1;
+        if data & 1 != 0 {
+            offset.checked_sub(distance)
+        } else {
+            offset.checked_add(distance)
+        }
+        .ok_or(PatchError::InvalidData)
+    };
+
+    while r.position < end {
+        let data = r.read_number()?;
+        let length = (data >> 2) + 1;
+        if target.len() + length > target_size {
+            return Err(PatchError::InvalidData);
+        }
+        match data & 3 {
+            0 => {
+                let start = target.len();
+                let bytes = rom
+                    .get(start..start + length)
+                    .ok_or(PatchError::InvalidData)?;
+                target.extend_from_slice(bytes);
+            }
+            1 => target.extend_from_slice(r.take(length)?),
+            2 => {
+                source_offset = relative(source_offset, r.read_number()?)?;
+                let bytes = rom
+                    .get(source_offset..source_offset + length)
+                    .ok_or(PatchError::InvalidData)?;
+                target.extend_from_slice(bytes);
+                source_offset += length;
+            }
+            _ => {
+                target_offset = relative(target_offset, r.read_number()?)?;
+                // コピー元とコピー先が重なることがあるので、1byteずつ
+                for _ in 0..length {
+                    let value = *target.get(target_offset).ok_or(PatchError::InvalidData)?;
+                    target.push(value);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(PatchError::InvalidData);
+    }
+    check_footer(rom, &target, patch)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn number(mut value: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let x = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    fn footer(source: &[u8], target: &[u8], mut patch: Vec<u8>) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_number() {
+        for value in [0, 1, 0x7F, 0x80, 0x4000, 0x123456] {
+            let data = number(value);
+            assert_eq!(PatchReader::new(&data, 0).read_number(), Ok(value));
+        }
+        // usize に収まらない
+        let data = vec![0x00; 16];
+        assert_eq!(
+            PatchReader::new(&data, 0).read_number(),
+            Err(PatchError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_ips() {
+        let rom = vec![0; 8];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply(&rom, &patch),
+            Ok(vec![0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC, 0xCC])
+        );
+
+        // 切り詰め
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        assert_eq!(apply(&rom, &patch), Ok(vec![0, 0xAA, 0xBB, 0]));
+
+        assert_eq!(
+            apply(&rom, &patch[..patch.len() - 7]),
+            Err(PatchError::UnexpectedEof)
+        );
+
+        // 16MB を超える
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0x00]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&rom, &patch), Err(PatchError::InvalidData));
+    }
+
+    #[test]
+    fn test_ups() {
+        let rom = vec![1, 2, 3, 4];
+        let target = vec![1, 9, 3, 4, 5];
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(5));
+        patch.extend(number(1));
+        patch.extend_from_slice(&[2 ^ 9, 0]);
+        // 終端の0も1byte進める
+        patch.extend(number(1));
+        patch.extend_from_slice(&[5, 0]);
+        let patch = footer(&rom, &target, patch);
+        assert_eq!(apply(&rom, &patch), Ok(target));
+
+        assert!(matches!(
+            apply(&[1, 2, 3, 5], &patch),
+            Err(PatchError::SourceChecksum { .. })
+        ));
+        let mut broken = patch.clone();
+        broken[6] ^= 1;
+        assert!(matches!(
+            apply(&rom, &broken),
+            Err(PatchError::PatchChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bps() {
+        let rom = vec![1, 2, 3, 4, 5, 6];
+        let target = vec![1, 2, 9, 5, 6, 9, 5, 6];
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(6));
+        patch.extend(number(8));
+        patch.extend(number(0));
+        // SourceRead 2
+        patch.extend(number((2 - 1) << 2));
+        // TargetRead 1
+        patch.extend(number(1));
+        patch.push(9);
+        // SourceCopy 2 (+4)
+        patch.extend(number((2 - 1) << 2 | 2));
+        patch.extend(number(4 << 1));
+        // TargetCopy 3 (+2)
+        patch.extend(number((3 - 1) << 2 | 3));
+        patch.extend(number(2 << 1));
+        let patch = footer(&rom, &target, patch);
+        assert_eq!(apply(&rom, &patch), Ok(target));
+
+        assert!(matches!(
+            apply(&[1, 2, 3, 4, 5, 7], &patch),
+            Err(PatchError::SourceChecksum { .. })
+        ));
+        assert_eq!(apply(&rom, b"hello"), Err(PatchError::UnknownFormat));
+    }
+}
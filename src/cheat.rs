@@ -0,0 +1,372 @@
+// チートコード (Game Genie / GameShark)
+//
+// Game Genie:  ROMの読み込みを書き換える。Cartridge::read_byte で当てる
+//   "ABC-DEF"      AB: 値, FCDE ^ 0xF000: アドレス
+//   "ABC-DEF-GHI"  GI: 比較する値 (元の値がこれと一致する場合だけ書き換える)
+// GameShark:   毎フレーム、RAMに値を書き込む。MemoryBus::poke_byte で書く
+//   "TTVVLLHH"     TT: 種類 (0x9X は WRAM のバンク X), VV: 値, HHLL: アドレス
+//
+// チートのファイル (1行に1つ。# から後ろはコメント)
+//   <コード>[+<コード>...] <名前>
+//   先頭に ! を付けると無効の状態で読み込む
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GameGenie {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GameShark {
+    // WRAM のバンク (CGB)。None の場合は SVBK で選んでいるバンク
+    pub bank: Option<u8>,
+    pub address: u16,
+    pub value: u8,
+}
+
+// "TTVVLLHH" の形式
+impl fmt::Display for GameShark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.bank {
+            Some(bank) => 0x90 | bank,
+            None => 0x01,
+        };
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}",
+            kind,
+            self.value,
+            self.address & 0xFF,
+            self.address >> 8
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CheatCode {
+    GameGenie(GameGenie),
+    GameShark(GameShark),
+}
+
+#[derive(Debug)]
+pub enum CheatError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    InvalidCode {
+        line: Option<usize>,
+        code: String,
+    },
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            CheatError::InvalidCode {
+                line: Some(line),
+                code,
+            } => write!(f, "line {}: invalid cheat code: {}", line, code),
+            CheatError::InvalidCode { line: None, code } => {
+                write!(f, "invalid cheat code: {}", code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+impl CheatCode {
+    pub fn parse(code: &str) -> Option<Self> {
+        let hex: Vec<u8> = code
+            .chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()?;
+        let byte = |i: usize| hex[i] << 4 | hex[i + 1];
+
+        match (hex.len(), code.contains('-')) {
+            (6, _) | (9, true) => {
+                // ABC-DEF-GHI
+                let address = ((hex[5] as u16 ^ 0xF) << 12)
+                    | (hex[2] as u16) << 8
+                    | (hex[3] as u16) << 4
+                    | hex[4] as u16;
+                if address >= 0x8000 {
+                    return None;
+                }
+                let compare = if hex.len() == 9 {
+                    Some((hex[6] << 4 | hex[8]).rotate_right(2) ^ 0xBA)
+                } else {
+                    None
+                };
+                Some(CheatCode::GameGenie(GameGenie {
+                    address,
+                    value: byte(0),
+                    compare,
+                }))
+            }
+            (8, false) => {
+                let kind = byte(0);
+                Some(CheatCode::GameShark(GameShark {
+                    bank: if kind & 0xF0 == 0x90 {
+                        Some(kind & 0x07)
+                    } else {
+                        None
+                    },
+                    address: (byte(6) as u16) << 8 | byte(4) as u16,
+                    value: byte(2),
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cheat {
+    pub name: String,
+    // 入力されたコード ("+" 区切り)
+    pub code: String,
+    pub enabled: bool,
+    codes: Vec<CheatCode>,
+}
+
+impl Cheat {
+    pub fn new(name: &str, code: &str) -> Result<Self, CheatError> {
+        let codes = code
+            .split('+')
+            .map(|part| {
+                CheatCode::parse(part.trim()).ok_or_else(|| CheatError::InvalidCode {
+                    line: None,
+                    code: part.trim().to_string(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Cheat {
+            name: name.to_string(),
+            code: code.to_string(),
+            enabled: true,
+            codes,
+        })
+    }
+
+    pub fn codes(&self) -> &[CheatCode] {
+        &self.codes
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats { cheats: Vec::new() }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, CheatError> {
+        let mut cheats = Cheats::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (enabled, line) = match line.strip_prefix('!') {
+                Some(line) => (false, line.trim_start()),
+                None => (true, line),
+            };
+            let (code, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let mut cheat = Cheat::new(name.trim(), code).map_err(|e| match e {
+                CheatError::InvalidCode { code, .. } => CheatError::InvalidCode {
+                    line: Some(i + 1),
+                    code,
+                },
+                e => e,
+            })?;
+            cheat.enabled = enabled;
+            cheats.cheats.push(cheat);
+        }
+        Ok(cheats)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CheatError> {
+        let text = std::fs::read_to_string(path).map_err(|error| CheatError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Self::parse(&text)
+    }
+
+    pub fn push(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        if index < self.cheats.len() {
+            Some(self.cheats.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Cheat> {
+        self.cheats.get(index)
+    }
+
+    // 範囲外の場合は false
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    fn enabled_codes(&self) -> impl Iterator<Item = &CheatCode> {
+        self.cheats
+            .iter()
+            .filter(|cheat| cheat.enabled)
+            .flat_map(|cheat| cheat.codes.iter())
+    }
+
+    pub fn game_genie(&self) -> Vec<GameGenie> {
+        self.enabled_codes()
+            .filter_map(|code| match code {
+                CheatCode::GameGenie(code) => Some(*code),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn game_shark(&self) -> Vec<GameShark> {
+        self.enabled_codes()
+            .filter_map(|code| match code {
+                CheatCode::GameShark(code) => Some(*code),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.cheats.len() as u32);
+        for cheat in &self.cheats {
+            w.write_string(&cheat.name);
+            w.write_string(&cheat.code);
+            w.write_bool(cheat.enabled);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        let count = r.read_u32()?;
+        let mut cheats = Vec::new();
+        for _ in 0..count {
+            let name = r.read_string()?;
+            let code = r.read_string()?;
+            let mut cheat = Cheat::new(&name, &code).map_err(|_| SaveStateError::InvalidFormat)?;
+            cheat.enabled = r.read_bool()?;
+            cheats.push(cheat);
+        }
+        self.cheats = cheats;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_game_genie() {
+        // 00A-17B-C49: 0x4A17 の 0xC8 を 0x00 にする
+        assert_eq!(
+            CheatCode::parse("00A-17B-C49"),
+            Some(CheatCode::GameGenie(GameGenie {
+                address: 0x4A17,
+                value: 0x00,
+                compare: Some(0xC8),
+            }))
+        );
+        assert_eq!(
+            CheatCode::parse("3EA-17B"),
+            Some(CheatCode::GameGenie(GameGenie {
+                address: 0x4A17,
+                value: 0x3E,
+                compare: None,
+            }))
+        );
+        // ROM の外
+        assert_eq!(CheatCode::parse("00A-170-C49"), None);
+    }
+
+    #[test]
+    fn test_game_shark() {
+        assert_eq!(
+            CheatCode::parse("0163E1C0"),
+            Some(CheatCode::GameShark(GameShark {
+                bank: None,
+                address: 0xC0E1,
+                value: 0x63,
+            }))
+        );
+        assert_eq!(
+            CheatCode::parse("9301A0D2"),
+            Some(CheatCode::GameShark(GameShark {
+                bank: Some(3),
+                address: 0xD2A0,
+                value: 0x01,
+            }))
+        );
+        let code = GameShark {
+            bank: Some(3),
+            address: 0xD2A0,
+            value: 0x01,
+        };
+        assert_eq!(code.to_string(), "9301A0D2");
+        assert_eq!(CheatCode::parse("0163E1C"), None);
+        assert_eq!(CheatCode::parse("0163E1CG"), None);
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let text = "# コメント
+0163E1C0+9301A0D2 Infinite money
+!00A-17B-C49   Walk through walls # 無効
+
+";
+        let cheats = Cheats::parse(text).unwrap();
+        assert_eq!(cheats.len(), 2);
+        assert_eq!(cheats.get(0).unwrap().name, "Infinite money");
+        assert_eq!(cheats.get(0).unwrap().codes().len(), 2);
+        assert!(!cheats.get(1).unwrap().enabled);
+        assert_eq!(cheats.game_shark().len(), 2);
+        assert_eq!(cheats.game_genie().len(), 0);
+
+        assert!(matches!(
+            Cheats::parse("0163E1C0 ok\nXYZ ng"),
+            Err(CheatError::InvalidCode { line: Some(2), .. })
+        ));
+    }
+}
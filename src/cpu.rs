@@ -1,13 +1,12 @@
 use core::panic;
 use std::env::args_os;
 
-use sdl2::audio::AudioQueue;
-
 use crate::{
     cartridge::Cartridge,
     instruction::{self, FlagValue, Flags},
     memory_bus::MemoryBus,
-    ppu::PPUInterrupt,
+    savestate::{SaveStateError, StateReader, StateWriter},
+    trace::Tracer,
 };
 
 pub struct Registers {
@@ -117,72 +116,161 @@ impl instruction::Instruction {
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HardwareMode {
+    DMG,
+    CGB,
+}
+
 pub struct CPU {
     pub registers: Registers,
     pub pc: u16,
     pub sp: u16,
     pub bus: MemoryBus,
     pub is_halted: bool,
+    // IME=0 で割り込みがあるときに HALT した。次の命令の読み込みで PC が進まない
+    pub halt_bug: bool,
+    // STOP で止まっている。ボタンが押されると戻る
+    pub is_stopped: bool,
+    // 不正な命令を実行して止まっている。リセットするまで戻らない
+    pub is_locked: bool,
+    // 止まったときの PC と命令。take_illegal_opcode で取り出す
+    illegal_opcode: Option<(u16, u8)>,
     pub ime_flag: bool,
-    pub setei: u8,
-    timer_counter: u16,
-    div_counter: u16,
-    clock_select: u8,
-
-    high_speed_mode: bool,
+    // EI を実行した。次の命令が終わってから IME が 1 になる
+    pub ime_scheduled: bool,
+    // None ならトレースしない
+    tracer: Option<Tracer>,
+    // トレースの書き込みに失敗した。take_trace_error で取り出す
+    trace_error: Option<std::io::ErrorKind>,
 }
 
 impl CPU {
-    pub fn new(cartridge: Cartridge, device: AudioQueue<f32>) -> Self {
+    pub fn new(cartridge: Cartridge, sample_rate: i32, mode: HardwareMode) -> Self {
         let mut cpu = CPU {
             registers: Registers::new(),
             pc: 0x0100,
             sp: 0xFFFE,
-            bus: MemoryBus::new(cartridge, device),
+            bus: MemoryBus::new(cartridge, sample_rate),
             is_halted: false,
+            halt_bug: false,
+            is_stopped: false,
+            is_locked: false,
+            illegal_opcode: None,
             ime_flag: true,
-            setei: 0,
-            timer_counter: 0,
-            div_counter: 0,
-            clock_select: 0,
-            high_speed_mode: false, // カラー対応。CPUスピード
+            ime_scheduled: false,
+            tracer: None,
+            trace_error: None,
         };
         // BOOT ROMを実行したフラグ的なやつを立てる。（白黒）
-        cpu.bus.write_byte(0xFF50, 1);
+        cpu.bus.poke_byte(0xFF50, 1);
+
+        if mode == HardwareMode::DMG {
+            // 白黒ゲームボーイとして起動する。
+            cpu.bus.poke_byte(0xFF4C, 0x04);
+            cpu.bus.ppu.opri = true;
+            return cpu;
+        }
 
         // ゲームボーイカラー対応機種ということをソフト側に伝える
         cpu.registers.a = 0x11;
-        cpu.bus.write_byte(0xFF50, 0x11); // カラーの場合は0x11を書き込む。
+        cpu.bus.poke_byte(0xFF50, 0x11); // カラーの場合は0x11を書き込む。
 
         // KEY0の初期化
-        let key0 = cpu.bus.read_byte(0x0143);
-        println!("KEY0: {:02X}", key0);
+        let key0 = cpu.bus.peek_byte(0x0143);
         if (key0 & 0x80) != 0 {
-            cpu.bus.write_byte(0xFF4C, key0);
+            cpu.bus.poke_byte(0xFF4C, key0);
         } else {
-            cpu.bus.write_byte(0xFF4C, 0x04);
+            cpu.bus.poke_byte(0xFF4C, 0x04);
             cpu.bus.ppu.opri = true;
         }
 
         cpu
     }
 
-    fn execute(&mut self, instruction: instruction::Instruction) {
+    // カラー対応。CPUスピード
+    pub fn high_speed_mode(&self) -> bool {
+        self.bus.high_speed_mode
+    }
+
+    // 命令ごとのトレースを始める (None で止める)。それまでのトレースを返す
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    // 前回呼んでから不正な命令で止まっていれば、その PC と命令
+    pub fn take_illegal_opcode(&mut self) -> Option<(u16, u8)> {
+        self.illegal_opcode.take()
+    }
+
+    // 前回呼んでからトレースの書き込みに失敗していれば、そのエラー
+    pub fn take_trace_error(&mut self) -> Option<std::io::ErrorKind> {
+        self.trace_error.take()
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.registers.a);
+        w.write_u8(self.registers.b);
+        w.write_u8(self.registers.c);
+        w.write_u8(self.registers.d);
+        w.write_u8(self.registers.e);
+        w.write_u8(u8::from(self.registers.f));
+        w.write_u8(self.registers.h);
+        w.write_u8(self.registers.l);
+        w.write_u16(self.pc);
+        w.write_u16(self.sp);
+        w.write_bool(self.is_halted);
+        w.write_bool(self.halt_bug);
+        w.write_bool(self.is_stopped);
+        w.write_bool(self.is_locked);
+        w.write_bool(self.ime_flag);
+        w.write_bool(self.ime_scheduled);
+        self.bus.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.registers.a = r.read_u8()?;
+        self.registers.b = r.read_u8()?;
+        self.registers.c = r.read_u8()?;
+        self.registers.d = r.read_u8()?;
+        self.registers.e = r.read_u8()?;
+        self.registers.f = FlagsRegister::from(r.read_u8()?);
+        self.registers.h = r.read_u8()?;
+        self.registers.l = r.read_u8()?;
+        self.pc = r.read_u16()?;
+        self.sp = r.read_u16()?;
+        self.is_halted = r.read_bool()?;
+        self.halt_bug = r.read_bool()?;
+        self.is_stopped = r.read_bool()?;
+        self.is_locked = r.read_bool()?;
+        self.ime_flag = r.read_bool()?;
+        self.ime_scheduled = r.read_bool()?;
+        self.bus.load_state(r)
+    }
+
+    // 実行にかかったサイクル数を返す。条件分岐は分岐したかどうかで変わる
+    fn execute(&mut self, instruction: instruction::Instruction, byte: u8, prefixed: bool) -> u16 {
         match instruction {
             instruction::Instruction::DEC(arg0, flags) => self.dec(arg0, flags),
-            instruction::Instruction::JP(arg0, arg1, flags) => self.jp(arg0, arg1, flags),
+            instruction::Instruction::JP(arg0, arg1, flags) => return self.jp(arg0, arg1, flags),
             instruction::Instruction::DAA(flags) => self.daa(flags),
             instruction::Instruction::SBC(arg0, arg1, flags) => self.sbc(arg0, arg1, flags),
             instruction::Instruction::SWAP(arg0, flags) => self.swap(arg0, flags),
             instruction::Instruction::SUB(arg0, flags) => self.sub(arg0, flags),
             instruction::Instruction::RETI(flags) => self.reti(flags),
-            instruction::Instruction::CALL(arg0, arg1, flags) => self.call(arg0, arg1, flags),
+            instruction::Instruction::CALL(arg0, arg1, flags) => {
+                return self.call(arg0, arg1, flags)
+            }
             instruction::Instruction::NOP(flags) => self.nop(flags),
             instruction::Instruction::CP(arg0, flags) => self.cp(arg0, flags),
             instruction::Instruction::RRCA(flags) => self.rrca(flags),
-            instruction::Instruction::RET(arg0, flags) => self.ret(arg0, flags),
+            instruction::Instruction::RET(arg0, flags) => return self.ret(arg0, flags),
             instruction::Instruction::SLA(arg0, flags) => self.sla(arg0, flags),
-            instruction::Instruction::JR(arg0, arg1, flags) => self.jr(arg0, arg1, flags),
+            instruction::Instruction::JR(arg0, arg1, flags) => return self.jr(arg0, arg1, flags),
             instruction::Instruction::PREFIX(arg0, flags) => self.prefix(arg0, flags),
             instruction::Instruction::SET(arg0, arg1, flags) => self.set(arg0, arg1, flags),
             instruction::Instruction::DI(flags) => self.di(flags),
@@ -215,6 +303,7 @@ impl CPU {
             instruction::Instruction::OR(arg0, flags) => self.or(arg0, flags),
             instruction::Instruction::RLC(arg0, flags) => self.rlc(arg0, flags),
         }
+        instruction::instruction_cycles(byte, prefixed)
     }
 
     fn jp(
@@ -222,14 +311,20 @@ impl CPU {
         arg0: instruction::JP_Arg_0,
         arg1: instruction::JP_Arg_1,
         flags: instruction::Flags,
-    ) {
-        if arg0.condition(self) {
-            self.pc = match arg0 {
-                instruction::JP_Arg_0::HL => self.registers.get_hl().wrapping_sub(1),
-                _ => self.read_next_word().wrapping_sub(3),
-            };
-        }
+    ) -> u16 {
         self.update_flags(0, flags);
+        if arg0 == instruction::JP_Arg_0::HL {
+            self.pc = self.registers.get_hl().wrapping_sub(1);
+            return 4;
+        }
+        let address = self.read_next_word();
+        if !arg0.condition(self) {
+            return 12;
+        }
+        // PC を書き換える M-cycle
+        self.bus.tick();
+        self.pc = address.wrapping_sub(3);
+        16
     }
 
     fn call(
@@ -237,24 +332,44 @@ impl CPU {
         arg0: instruction::CALL_Arg_0,
         arg1: instruction::CALL_Arg_1,
         flags: instruction::Flags,
-    ) {
-        if arg0.condition(self) {
-            self.push_u16(self.pc.wrapping_add(3));
-            // 引いてるのは後で足すから。
-            self.pc = self.read_next_word().wrapping_sub(3)
-        }
+    ) -> u16 {
         self.update_flags(0, flags);
-    }
-    fn ret(&mut self, arg0: instruction::RET_Arg_0, flags: instruction::Flags) {
-        if arg0.condition(self) {
-            let pc = self.pop_u16();
-            // 共通処理でPCがbyte数足されるので、それを考慮して引いておく。
-            self.pc = pc.wrapping_sub(1)
+        let address = self.read_next_word();
+        if !arg0.condition(self) {
+            return 12;
         }
+        // SP を減らす M-cycle の後で push する
+        self.bus.tick();
+        self.push_u16(self.pc.wrapping_add(3));
+        // 引いてるのは後で足すから。
+        self.pc = address.wrapping_sub(3);
+        24
+    }
+    fn ret(&mut self, arg0: instruction::RET_Arg_0, flags: instruction::Flags) -> u16 {
         self.update_flags(0, flags);
+        // 条件付きの場合は、pop の前に条件を確認する M-cycle がある
+        let conditional = arg0 != instruction::RET_Arg_0::NONE;
+        if conditional {
+            self.bus.tick();
+        }
+        if !arg0.condition(self) {
+            return 8;
+        }
+        let pc = self.pop_u16();
+        // PC を書き換える M-cycle
+        self.bus.tick();
+        // 共通処理でPCがbyte数足されるので、それを考慮して引いておく。
+        self.pc = pc.wrapping_sub(1);
+        if conditional {
+            20
+        } else {
+            16
+        }
     }
     fn push(&mut self, arg0: instruction::PUSH_Arg_0, flags: instruction::Flags) {
         let value = arg0.get_value(self);
+        // SP を減らす M-cycle の後で push する
+        self.bus.tick();
         self.push_u16(value);
         self.update_flags(value, flags);
     }
@@ -277,6 +392,12 @@ impl CPU {
         flags: instruction::Flags,
     ) {
         let source_value = arg1.get_value(self);
+        // LD HL, SP+r8 と LD SP, HL は 16bit の計算 / 転送に 1 M-cycle かかる
+        if arg1 == instruction::LD_Arg_1::SP_r8
+            || (arg0 == instruction::LD_Arg_0::SP && arg1 == instruction::LD_Arg_1::HL)
+        {
+            self.bus.tick();
+        }
         arg0.set_value(self, source_value);
         self.update_flags(source_value, flags);
     }
@@ -295,6 +416,8 @@ impl CPU {
             }
             instruction::ADD_Arg_0::HL => {
                 let value = arg1.get_value(self);
+                // 16bit の加算に 1 M-cycle かかる
+                self.bus.tick();
                 // TODO ここの処理を関数化する ==> update_carry_u16
                 let (new_value, did_overflow) = self.registers.get_hl().overflowing_add(value);
                 self.registers.f.carry = did_overflow;
@@ -306,6 +429,9 @@ impl CPU {
             }
             instruction::ADD_Arg_0::SP => {
                 let value = arg1.get_value(self) as u8;
+                // 下位と上位の計算に 1 M-cycle ずつかかる
+                self.bus.tick();
+                self.bus.tick();
                 self.sp = self.add_e8(self.sp, value);
                 self.update_flags(self.sp, flags);
             }
@@ -314,6 +440,16 @@ impl CPU {
 
     fn dec(&mut self, arg0: instruction::DEC_Arg_0, flags: instruction::Flags) {
         let value = arg0.get_value(self);
+        // 16bit レジスタの計算に 1 M-cycle かかる
+        if matches!(
+            arg0,
+            instruction::DEC_Arg_0::BC
+                | instruction::DEC_Arg_0::DE
+                | instruction::DEC_Arg_0::HL
+                | instruction::DEC_Arg_0::SP
+        ) {
+            self.bus.tick();
+        }
         let sub = value.wrapping_sub(1);
         arg0.set_value(self, sub);
         if flags.half_carry == FlagValue::CHANGE {
@@ -379,7 +515,8 @@ impl CPU {
         self.update_flags(self.registers.a as u16, flags);
     }
     fn reti(&mut self, flags: instruction::Flags) {
-        self.setei = 1;
+        // EI と違ってすぐに有効になる
+        self.ime_flag = true;
         self.ret(
             instruction::RET_Arg_0::NONE,
             Flags {
@@ -421,11 +558,16 @@ impl CPU {
         arg0: instruction::JR_Arg_0,
         arg1: instruction::JR_Arg_1,
         flags: instruction::Flags,
-    ) {
-        if arg0.condition(self) {
-            self.pc = ((self.pc as i32) + ((self.read_next_byte() as i8) as i32)) as u16;
-        }
+    ) -> u16 {
         self.update_flags(0, flags);
+        let offset = self.read_next_byte() as i8;
+        if !arg0.condition(self) {
+            return 8;
+        }
+        // PC を計算する M-cycle
+        self.bus.tick();
+        self.pc = ((self.pc as i32) + (offset as i32)) as u16;
+        12
     }
     fn prefix(&mut self, arg0: instruction::PREFIX_Arg_0, flags: instruction::Flags) {
         panic!("call CB !!");
@@ -453,6 +595,7 @@ impl CPU {
     }
     fn di(&mut self, flags: instruction::Flags) {
         self.ime_flag = false;
+        self.ime_scheduled = false;
     }
     fn rrc(&mut self, arg0: instruction::RRC_Arg_0, flags: instruction::Flags) {
         let value = arg0.get_value(self) as u8;
@@ -468,6 +611,16 @@ impl CPU {
     }
     fn inc(&mut self, arg0: instruction::INC_Arg_0, flags: instruction::Flags) {
         let value = arg0.get_value(self);
+        // 16bit レジスタの計算に 1 M-cycle かかる
+        if matches!(
+            arg0,
+            instruction::INC_Arg_0::BC
+                | instruction::INC_Arg_0::DE
+                | instruction::INC_Arg_0::HL
+                | instruction::INC_Arg_0::SP
+        ) {
+            self.bus.tick();
+        }
         let add = value.wrapping_add(1);
         let add = arg0.set_value(self, add);
         if flags.half_carry == FlagValue::CHANGE {
@@ -486,6 +639,8 @@ impl CPU {
             instruction::RST_Arg_0::_30H => 0x0030,
             instruction::RST_Arg_0::_38H => 0x0038,
         } as u16;
+        // SP を減らす M-cycle の後で push する
+        self.bus.tick();
         self.push_u16(self.pc.wrapping_add(1));
         self.pc = addr.wrapping_sub(1);
     }
@@ -516,7 +671,12 @@ impl CPU {
         self.update_flags(self.registers.a as u16, flags);
     }
     fn halt(&mut self, flags: instruction::Flags) {
-        self.is_halted = true
+        // IME=0 で割り込みが来ていると HALT せずに、次の命令の読み込みで PC が進まない
+        if !self.ime_flag && self.pending_interrupts() != 0 {
+            self.halt_bug = true;
+        } else {
+            self.is_halted = true;
+        }
     }
     fn xor(&mut self, arg0: instruction::XOR_Arg_0, flags: instruction::Flags) {
         let value = arg0.get_value(self) as u8;
@@ -560,16 +720,18 @@ impl CPU {
         self.registers.f.carry = carry;
     }
     fn stop(&mut self, arg0: instruction::STOP_Arg_0, flags: instruction::Flags) {
-        println!("CALL STOP");
         // 速度切り替えを行う
-        let key1 = self.bus.read_byte(0xFF4D);
+        let key1 = self.bus.peek_byte(0xFF4D);
         let curret_speed = key1 & 0x80 != 0; // true=倍速
         let switch_speed = key1 & 0x01 != 0; // true=切り替え準備
         if switch_speed {
             // 速度切り替え実行
-            self.high_speed_mode = !curret_speed;
+            self.bus.high_speed_mode = !curret_speed;
             self.bus
-                .write_byte(0xFF4D, if self.high_speed_mode { 0x80 } else { 0x00 });
+                .poke_byte(0xFF4D, if self.bus.high_speed_mode { 0x80 } else { 0x00 });
+        } else if self.bus.joypad.lines() == 0x0F {
+            // ボタンが押されるまで止まる。押されたままの場合は止まらない
+            self.is_stopped = true;
         }
     }
     fn ccf(&mut self, flags: instruction::Flags) {
@@ -662,7 +824,7 @@ impl CPU {
         self.update_flags(self.registers.a as u16, flags);
     }
     fn ei(&mut self, flags: instruction::Flags) {
-        self.setei = 2;
+        self.ime_scheduled = true;
     }
 
     fn or(&mut self, arg0: instruction::OR_Arg_0, flags: instruction::Flags) {
@@ -731,74 +893,89 @@ impl CPU {
         };
     }
 
-    pub fn step(&mut self) {
-        if self.setei != 0 {
-            self.setei -= 1;
-            if self.setei == 0 {
-                self.ime_flag = true;
+    pub fn step(&mut self) -> u16 {
+        // STOP 中はタイマーも画面も止まる。選択しているボタンのどれかが押されたら戻る
+        if self.is_stopped {
+            if self.bus.joypad.lines() == 0x0F {
+                return 4;
             }
+            self.is_stopped = false;
+        }
+
+        // メモリにアクセスするたびに 1 M-cycle 進むので、命令の途中の読み書きも正しいタイミングになる
+        let start = self.bus.cycles();
+
+        // 不正な命令で止まった CPU は割り込みも受け付けない。画面やタイマーは動き続ける
+        if self.is_locked {
+            self.bus.tick();
+            return 4;
         }
 
         if self.is_halted {
-            self.update_timers(1);
-            self.update_graphics(1);
-            self.update_audio();
+            self.bus.tick();
             self.do_interrupts();
-            return;
+            return (self.bus.cycles() - start) as u16;
         }
 
+        // 書き込めなくなったらトレースをやめる
+        if let Some(mut tracer) = self.tracer.take() {
+            match tracer.write(self) {
+                Ok(()) => self.tracer = Some(tracer),
+                Err(e) => self.trace_error = Some(e.kind()),
+            }
+        }
+
+        let address = self.pc;
         let mut instruction_byte = self.bus.read_byte(self.pc);
+        // HALT バグ: PC が進まないので、同じバイトをもう一度読む
+        if std::mem::take(&mut self.halt_bug) {
+            self.pc = self.pc.wrapping_sub(1);
+        }
         let prefixed = instruction_byte == 0xCB;
         if prefixed {
-            instruction_byte = self.bus.read_byte(self.pc + 1);
+            instruction_byte = self.bus.read_byte(self.pc.wrapping_add(1));
         }
-        if let Some(instruction) = instruction::Instruction::from_byte(instruction_byte, prefixed) {
-            let description = format!(
-                "0x{}{:02X}(0x{:02X})",
-                if prefixed { "CB" } else { "" },
-                instruction_byte,
-                self.read_next_byte(),
-            );
-            if instruction_byte != 0x00 {
-                println!(
-                    "{:04X} ==> {} AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} ({:02X} {:02X})",
-                    self.pc,
-                    description,
-                    self.registers.get_af(),
-                    self.registers.get_bc(),
-                    self.registers.get_de(),
-                    self.registers.get_hl(),
-                    self.sp,
-                    self.bus.memory[self.sp as usize - 2], self.bus.memory[self.sp as usize - 1]
-                );
-            }
-            self.execute(instruction);
+        let cycles = if let Some(instruction) =
+            instruction::Instruction::from_byte(instruction_byte, prefixed)
+        {
+            let cycles = self.execute(instruction, instruction_byte, prefixed);
             self.pc = self
                 .pc
                 .wrapping_add(instruction::instruction_bytes(instruction_byte, prefixed));
+            cycles
         } else {
-            let description = format!(
-                "0x{}{:02X}",
-                if prefixed { "CB" } else { "" },
-                instruction_byte
-            );
-            panic!("Unknown instruction found for: {}", description)
+            // 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB-0xED, 0xF4, 0xFC, 0xFD は実機では CPU が止まる
+            self.is_locked = true;
+            self.illegal_opcode = Some((address, instruction_byte));
+            self.pc = address;
+            return (self.bus.cycles() - start) as u16;
         };
 
-        let cycles = instruction::instruction_cycles(instruction_byte, prefixed);
-        self.update_timers(cycles);
-        self.update_graphics(cycles);
-        self.update_audio();
+        // メモリにアクセスしない M-cycle (16bit の演算やジャンプなど) も各命令の中で進めている
+        debug_assert_eq!(
+            (self.bus.cycles() - start) as u16,
+            cycles,
+            "opcode {}{:02X}",
+            if prefixed { "CB " } else { "" },
+            instruction_byte
+        );
+
+        // EI の直後の命令の後では、まだ割り込まない
+        let ime_scheduled = std::mem::take(&mut self.ime_scheduled);
         self.do_interrupts();
+        if ime_scheduled {
+            self.ime_flag = true;
+        }
+        (self.bus.cycles() - start) as u16
     }
 
     pub fn read_next_byte(&mut self) -> u8 {
-        self.bus.read_byte(self.pc + 1)
+        self.bus.read_byte(self.pc.wrapping_add(1))
     }
 
     pub fn read_next_word(&mut self) -> u16 {
-        let l = self.bus.read_byte(self.pc + 1) as u16;
-        let u = self.bus.read_byte(self.pc + 2) as u16;
+        let l = self.bus.read_byte(self.pc.wrapping_add(1)) as u16;
+        let u = self.bus.read_byte(self.pc.wrapping_add(2)) as u16;
         return (u << 8) | l;
     }
 
@@ -817,10 +994,13 @@ impl CPU {
         (msb << 8) | lsb
     }
 
-    fn do_interrupts(&mut self) {
-        let request = self.bus.read_byte(0xFF0F) & self.bus.read_byte(0xFFFF);
+    // 要求されていて、許可されている割り込み
+    fn pending_interrupts(&mut self) -> u8 {
+        self.bus.peek_byte(0xFF0F) & self.bus.peek_byte(0xFFFF) & 0x1F
+    }
 
-        if request == 0 {
+    fn do_interrupts(&mut self) {
+        if self.pending_interrupts() == 0 {
             return;
         }
 
@@ -830,106 +1010,42 @@ impl CPU {
             return;
         }
 
-        for i in 0..=4 {
-            let mask = (0x01 << i) as u8;
-            if (request & mask) != 0 {
-                self.service_interrupt(i as u8);
-                break;
-            }
-        }
+        self.service_interrupt();
     }
 
-    fn service_interrupt(&mut self, interrupt: u8) {
+    // 割り込みの処理は 5 M-cycle かかる
+    //   M1, M2: 待ち  M3: PC の上位を push  M4: PC の下位を push  M5: PC を設定
+    // 上位の push で IE (0xFFFF) を書き換えると、割り込み先が変わったり取り消されたりする。
+    // 取り消された場合は 0x0000 にジャンプし、IF は消さない。
+    fn service_interrupt(&mut self) {
         self.ime_flag = false;
-        self.setei = 0;
-        let request = self.bus.read_byte(0xFF0F);
-        let mask = (0x01 << interrupt) as u8;
-        self.bus.write_byte(0xFF0F, request & !mask);
-
-        self.push_u16(self.pc);
-
-        self.pc = match interrupt {
-            0 => 0x0040,
-            1 => 0x0048,
-            2 => 0x0050,
-            3 => 0x0058,
-            4 => 0x0060,
-            _ => panic!("should not reach."),
-        }
-    }
-
-    fn request_interrupt(&mut self, id: u16) {
-        let req = self.bus.read_byte(0xFF0F);
-        let req = req | (0x01 << id);
-        self.bus.write_byte(0xFF0F, req);
-    }
+        self.bus.tick();
+        self.bus.tick();
 
-    fn update_graphics(&mut self, cycles: u16) {
-        match self.bus.ppu.update(cycles, self.high_speed_mode) {
-            PPUInterrupt::NONE => {}
-            PPUInterrupt::VBALNK => self.request_interrupt(0),
-            PPUInterrupt::LCD => self.request_interrupt(1),
-        }
-    }
-
-    fn update_audio(&mut self) {
-        self.bus
-            .apu
-            .update(self.bus.memory[0xFF04], self.high_speed_mode);
-    }
-
-    fn update_timers(&mut self, cycles: u16) {
-        self.do_div_register(cycles);
-
-        let tmc = self.bus.read_byte(0xFF07);
-        let enabled = tmc & 0x04 != 0;
-        let clock_select = tmc & 0x03;
-        let clock = match clock_select {
-            0b00 => 4194304 / 4096,
-            0b01 => 4194304 / 262144,
-            0b10 => 4194304 / 65536,
-            0b11 => 4194304 / 16384,
-            _ => panic!("should not reach"),
-        } as u16;
-
-        if self.clock_select != clock_select {
-            self.timer_counter = 0;
-            self.clock_select = clock_select;
-        }
-
-        if !enabled {
-            return;
-        }
-        self.timer_counter += cycles;
-        if self.timer_counter < clock {
-            return;
-        }
-        self.timer_counter -= clock;
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus.write_byte(self.sp, (self.pc >> 8) as u8);
+        let enabled = self.bus.peek_byte(0xFFFF);
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus.write_byte(self.sp, self.pc as u8);
 
-        let tima = self.bus.read_byte(0xFF05);
-        if tima == 255 {
-            let tma = self.bus.read_byte(0xFF06);
-            self.bus.write_byte(0xFF05, tma);
-            self.request_interrupt(2);
+        let request = self.bus.peek_byte(0xFF0F);
+        let pending = request & enabled & 0x1F;
+        self.pc = if pending == 0 {
+            0x0000
         } else {
-            self.bus.write_byte(0xFF05, tima + 1);
-        }
-    }
-
-    fn do_div_register(&mut self, cycles: u16) {
-        self.div_counter += cycles;
-        if self.div_counter >= 255 {
-            self.div_counter -= 255;
-            // 0xFF04にwrite_byteすると、必ず0になる仕様なので、
-            // 直接書き込む。
-            self.bus.memory[0xFF04] = self.bus.memory[0xFF04].wrapping_add(1);
-        }
+            // 番号の小さいものが優先
+            let interrupt = pending.trailing_zeros() as u16;
+            self.bus.poke_byte(0xFF0F, request & !(1 << interrupt));
+            0x0040 + interrupt * 8
+        };
+        self.bus.tick();
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::joypad::Buttons;
 
     fn F(zero: bool, subtract: bool, half_carry: bool, carry: bool) -> FlagsRegister {
         FlagsRegister {
@@ -941,7 +1057,7 @@ mod test {
     }
 
     fn newCPU() -> CPU {
-        let mut cpu = CPU::new(Cartridge::for_test());
+        let mut cpu = CPU::new(Cartridge::for_test(), 44100, HardwareMode::CGB);
         cpu.pc = 0x0000;
         cpu.registers.f = FlagsRegister::from(0x00);
         cpu
@@ -2213,7 +2329,7 @@ mod test {
         cpu.sp = 0x1040;
         cpu.step();
         assert_eq!(cpu.registers.get_hl(), 0x1040 + 0x67);
-        assert_eq!(cpu.sp, 0x1040 + 0x67);
+        assert_eq!(cpu.sp, 0x1040);
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
@@ -2250,7 +2366,7 @@ mod test {
         cpu.bus.write_byte(0x0001, 0xFF); // args
         cpu.sp = 0x1040;
         cpu.step();
-        assert_eq!(cpu.sp, 0x1040 - 1);
+        assert_eq!(cpu.sp, 0x1040);
         assert_eq!(cpu.registers.get_hl(), 0x1040 - 1);
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, true));
@@ -4313,8 +4429,8 @@ mod test {
         let mut cpu = newCPU();
         cpu.bus.write_byte(0x0000, 0xC7); // RST _00H
         cpu.step();
-        // FIXME
-        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.pop_u16(), 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
 
@@ -4323,8 +4439,8 @@ mod test {
         let mut cpu = newCPU();
         cpu.bus.write_byte(0x0000, 0xCF); // RST _08H
         cpu.step();
-        // FIXME
-        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.pc, 0x0008);
+        assert_eq!(cpu.pop_u16(), 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
 
@@ -4333,8 +4449,8 @@ mod test {
         let mut cpu = newCPU();
         cpu.bus.write_byte(0x0000, 0xD7); // RST _10H
         cpu.step();
-        // FIXME
-        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.pc, 0x0010);
+        assert_eq!(cpu.pop_u16(), 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
 
@@ -4343,8 +4459,8 @@ mod test {
         let mut cpu = newCPU();
         cpu.bus.write_byte(0x0000, 0xDF); // RST _18H
         cpu.step();
-        // FIXME
-        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.pc, 0x0018);
+        assert_eq!(cpu.pop_u16(), 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
 
@@ -4353,8 +4469,8 @@ mod test {
         let mut cpu = newCPU();
         cpu.bus.write_byte(0x0000, 0xE7); // RST _20H
         cpu.step();
-        // FIXME
-        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.pc, 0x0020);
+        assert_eq!(cpu.pop_u16(), 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
 
@@ -4363,8 +4479,8 @@ mod test {
         let mut cpu = newCPU();
         cpu.bus.write_byte(0x0000, 0xEF); // RST _28H
         cpu.step();
-        // FIXME
-        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.pc, 0x0028);
+        assert_eq!(cpu.pop_u16(), 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
 
@@ -4373,8 +4489,8 @@ mod test {
         let mut cpu = newCPU();
         cpu.bus.write_byte(0x0000, 0xF7); // RST _30H
         cpu.step();
-        // FIXME
-        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.pc, 0x0030);
+        assert_eq!(cpu.pop_u16(), 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
 
@@ -4383,8 +4499,8 @@ mod test {
         let mut cpu = newCPU();
         cpu.bus.write_byte(0x0000, 0xFF); // RST _38H
         cpu.step();
-        // FIXME
-        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.pc, 0x0038);
+        assert_eq!(cpu.pop_u16(), 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
 
@@ -4464,4 +4580,444 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
     }
+
+    #[test]
+    fn test_joypad_interrupt() {
+        let mut cpu = newCPU();
+        cpu.bus.write_byte(0xFF0F, 0x00);
+
+        // 十字キーを選択して A を押しても割り込みは起きない
+        cpu.bus.write_byte(0xFF00, 0x20);
+        cpu.bus.set_buttons(Buttons {
+            a: true,
+            ..Buttons::default()
+        });
+        assert_eq!(cpu.bus.read_byte(0xFF0F) & 0x10, 0x00);
+
+        // 押されているボタンを選択すると Low になる
+        cpu.bus.write_byte(0xFF00, 0x10);
+        assert_eq!(cpu.bus.read_byte(0xFF00) & 0x0F, 0x0E);
+        assert_eq!(cpu.bus.read_byte(0xFF0F) & 0x10, 0x10);
+
+        // 離したときは起きない
+        cpu.bus.write_byte(0xFF0F, 0x00);
+        cpu.bus.set_buttons(Buttons::default());
+        assert_eq!(cpu.bus.read_byte(0xFF0F) & 0x10, 0x00);
+
+        cpu.bus.set_buttons(Buttons {
+            start: true,
+            ..Buttons::default()
+        });
+        assert_eq!(cpu.bus.read_byte(0xFF0F) & 0x10, 0x10);
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut cpu = newCPU();
+        cpu.ime_flag = false;
+        cpu.bus.write_byte(0x0000, 0x10); // STOP
+        cpu.bus.write_byte(0x0001, 0x00);
+        cpu.bus.write_byte(0x0002, 0x3C); // INC A
+        cpu.bus.write_byte(0xFF00, 0x10);
+        cpu.step();
+        assert!(cpu.is_stopped);
+        assert_eq!(cpu.pc, 0x0002);
+
+        // ボタンが押されるまで進まない
+        let div = cpu.bus.read_byte(0xFF04);
+        for _ in 0..1000 {
+            cpu.step();
+        }
+        assert_eq!(cpu.pc, 0x0002);
+        assert_eq!(cpu.bus.read_byte(0xFF04), div);
+
+        cpu.bus.set_buttons(Buttons {
+            b: true,
+            ..Buttons::default()
+        });
+        cpu.step();
+        assert!(!cpu.is_stopped);
+        assert_eq!(cpu.pc, 0x0003);
+        assert_eq!(cpu.registers.a, 0x12);
+
+        // ボタンが押されたままの場合は止まらない
+        cpu.pc = 0x0000;
+        cpu.step();
+        assert!(!cpu.is_stopped);
+    }
+
+    #[test]
+    fn test_stop_both_selected() {
+        // P1=0x00 (両方選択) で STOP しても、どちらかのボタンで起きる
+        let mut cpu = newCPU();
+        cpu.ime_flag = false;
+        cpu.bus.write_byte(0x0000, 0x10); // STOP
+        cpu.bus.write_byte(0x0001, 0x00);
+        cpu.bus.write_byte(0xFF00, 0x00);
+        cpu.bus.write_byte(0xFF0F, 0x00);
+        cpu.step();
+        assert!(cpu.is_stopped);
+
+        cpu.bus.set_buttons(Buttons {
+            down: true,
+            a: true,
+            ..Buttons::default()
+        });
+        assert_eq!(cpu.bus.read_byte(0xFF00) & 0x0F, 0x06);
+        assert_eq!(cpu.bus.peek_byte(0xFF0F) & 0x10, 0x10);
+        cpu.step();
+        assert!(!cpu.is_stopped);
+        assert_eq!(cpu.pc, 0x0003);
+    }
+
+    #[test]
+    fn test_memory_access_timing() {
+        // TIMA を 4 M-cycle ごとに増やす
+        let mut cpu = newCPU();
+        cpu.bus.poke_byte(0xFF07, 0x05);
+        cpu.bus.poke_byte(0xFF04, 0x00);
+        let start = cpu.bus.cycles();
+
+        // LD A, (a16) は 4 M-cycle 目に読む
+        cpu.bus.poke_byte(0x0000, 0xFA);
+        cpu.bus.poke_byte(0x0001, 0x05);
+        cpu.bus.poke_byte(0x0002, 0xFF);
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.registers.a, 0x01);
+
+        // LD HL, d16 (3 M-cycle) + LD A, (HL) の 2 M-cycle 目
+        cpu.bus.poke_byte(0x0003, 0x21);
+        cpu.bus.poke_byte(0x0004, 0x05);
+        cpu.bus.poke_byte(0x0005, 0xFF);
+        cpu.bus.poke_byte(0x0006, 0x7E);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x02);
+
+        // メモリにアクセスしない M-cycle も進む (INC HL は 2 M-cycle)
+        cpu.bus.poke_byte(0x0007, 0x23);
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.bus.cycles() - start, 16 + 12 + 8 + 8);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut cpu = newCPU();
+        for i in 0..0xA0 {
+            cpu.bus.poke_byte(0xC000 + i, i as u8);
+        }
+        cpu.bus.poke_byte(0xFE00, 0x55);
+        cpu.bus.write_byte(0xFF46, 0xC0);
+
+        // 次の M-cycle はまだ読める
+        assert_eq!(cpu.bus.read_byte(0xFE00), 0x55);
+        // 転送中は 0xFF
+        assert_eq!(cpu.bus.read_byte(0xFE00), 0xFF);
+        assert_eq!(cpu.bus.peek_byte(0xFE00), 0x00);
+        // HRAM は読める
+        cpu.bus.poke_byte(0xFF80, 0x12);
+        assert_eq!(cpu.bus.read_byte(0xFF80), 0x12);
+
+        cpu.bus.tick_cycles(4 * 157);
+        assert_eq!(cpu.bus.read_byte(0xFE9F), 0xFF);
+        assert_eq!(cpu.bus.read_byte(0xFE9F), 0x9F);
+    }
+
+    #[test]
+    fn test_conditional_cycles() {
+        // (opcode, 分岐する flags, 分岐しない flags, 分岐したときのサイクル数, しないときのサイクル数)
+        let NZ = (F(false, false, false, false), F(true, false, false, false));
+        let Z = (NZ.1, NZ.0);
+        let NC = (F(false, false, false, false), F(false, false, false, true));
+        let C = (NC.1, NC.0);
+        let cases = [
+            // JR cc, r8
+            (0x20, NZ, 12, 8),
+            (0x28, Z, 12, 8),
+            (0x30, NC, 12, 8),
+            (0x38, C, 12, 8),
+            // JP cc, a16
+            (0xC2, NZ, 16, 12),
+            (0xCA, Z, 16, 12),
+            (0xD2, NC, 16, 12),
+            (0xDA, C, 16, 12),
+            // CALL cc, a16
+            (0xC4, NZ, 24, 12),
+            (0xCC, Z, 24, 12),
+            (0xD4, NC, 24, 12),
+            (0xDC, C, 24, 12),
+            // RET cc
+            (0xC0, NZ, 20, 8),
+            (0xC8, Z, 20, 8),
+            (0xD0, NC, 20, 8),
+            (0xD8, C, 20, 8),
+        ];
+        for (opcode, (taken, not_taken), taken_cycles, not_taken_cycles) in cases {
+            let bytes = instruction::instruction_bytes(opcode, false);
+            // 分岐先: JR は +0x10、JP と CALL は 0x1234、RET はスタックの 0x1234
+            let target = if bytes == 2 { 0x0012 } else { 0x1234 };
+
+            for (flags, cycles, pc) in [
+                (taken, taken_cycles, target),
+                (not_taken, not_taken_cycles, bytes),
+            ] {
+                let mut cpu = newCPU();
+                cpu.bus.poke_byte(0x0000, opcode);
+                cpu.bus
+                    .poke_byte(0x0001, if bytes == 2 { 0x10 } else { 0x34 });
+                cpu.bus.poke_byte(0x0002, 0x12);
+                cpu.sp = 0xDFF0;
+                cpu.bus.poke_byte(0xDFF0, 0x34);
+                cpu.bus.poke_byte(0xDFF1, 0x12);
+                cpu.registers.f = flags;
+                let start = cpu.bus.cycles();
+                assert_eq!(cpu.step(), cycles, "opcode {:02X}", opcode);
+                assert_eq!(
+                    cpu.bus.cycles() - start,
+                    cycles as u64,
+                    "opcode {:02X}",
+                    opcode
+                );
+                assert_eq!(cpu.pc, pc, "opcode {:02X}", opcode);
+            }
+        }
+
+        // 分岐しなかった CALL はスタックを変えない
+        let mut cpu = newCPU();
+        cpu.bus.poke_byte(0x0000, 0xC4);
+        cpu.sp = 0xDFF0;
+        cpu.registers.f = F(true, false, false, false);
+        cpu.step();
+        assert_eq!(cpu.sp, 0xDFF0);
+    }
+
+    #[test]
+    fn test_all_instruction_cycles() {
+        // step の中でメモリアクセスと内部の M-cycle を数えた数が命令のサイクル数と合う (debug_assert)
+        for prefixed in [false, true] {
+            for byte in 0..=0xFF {
+                if instruction::Instruction::from_byte(byte, prefixed).is_none() {
+                    continue;
+                }
+                // 条件分岐は、する場合としない場合の両方
+                for flags in [0x00, 0xF0] {
+                    let mut cpu = newCPU();
+                    cpu.sp = 0xDFF0;
+                    cpu.registers.f = FlagsRegister::from(flags);
+                    if prefixed {
+                        cpu.bus.poke_byte(0x0000, 0xCB);
+                        cpu.bus.poke_byte(0x0001, byte);
+                    } else {
+                        cpu.bus.poke_byte(0x0000, byte);
+                    }
+                    cpu.step();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stack_access_timing() {
+        // OAM DMA の最後の M-cycle に内部の M-cycle が重なるように命令を始める。
+        // DMA 中の OAM への読み書きは無視されるので、内部の M-cycle より先にスタック (OAM) に
+        // アクセスすると失敗する
+        // (opcode, 内部の M-cycle より前の M-cycle 数, push される値)
+        let cases = [
+            (0xC5, 1, 0x1234), // PUSH BC
+            (0xFF, 1, 0x0151), // RST 38H
+            (0xCD, 3, 0x0153), // CALL a16
+        ];
+        for (opcode, before, value) in cases {
+            let mut cpu = newCPU();
+            cpu.pc = 0x0150;
+            cpu.sp = 0xFE02;
+            cpu.registers.set_bc(0x1234);
+            cpu.bus.poke_byte(0x0150, opcode);
+            cpu.bus.poke_byte(0xC000, 0xFF);
+            cpu.bus.poke_byte(0xC001, 0xFF);
+            cpu.bus.write_byte(0xFF46, 0xC0);
+            cpu.bus.tick_cycles(4 * (160 - before));
+            cpu.step();
+            assert_eq!(
+                cpu.bus.peek_byte(0xFE01),
+                (value >> 8) as u8,
+                "opcode {:02X}",
+                opcode
+            );
+            assert_eq!(
+                cpu.bus.peek_byte(0xFE00),
+                value as u8,
+                "opcode {:02X}",
+                opcode
+            );
+        }
+
+        // RET cc は条件を確認してから pop する
+        let mut cpu = newCPU();
+        cpu.sp = 0xFE00;
+        cpu.registers.f = F(false, false, false, false);
+        cpu.bus.poke_byte(0x0000, 0xC0); // RET NZ
+        cpu.bus.poke_byte(0xC000, 0x34);
+        cpu.bus.poke_byte(0xC001, 0x12);
+        cpu.bus.write_byte(0xFF46, 0xC0);
+        cpu.bus.tick_cycles(4 * 159);
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.pc, 0x1234);
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        let mut cpu = newCPU();
+        cpu.ime_flag = false;
+        cpu.sp = 0xDFF0;
+        cpu.bus.poke_byte(0xFFFF, 0x01);
+        cpu.bus.poke_byte(0xFF0F, 0x01);
+        cpu.bus.poke_byte(0x0000, 0xFB); // EI
+        cpu.bus.poke_byte(0x0001, 0x00); // NOP
+
+        // EI の直後には割り込まない
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.pc, 0x0001);
+        assert!(cpu.ime_flag);
+
+        // 次の命令の後に割り込む (NOP + 5 M-cycle)
+        assert_eq!(cpu.step(), 4 + 20);
+        assert_eq!(cpu.pc, 0x0040);
+        assert!(!cpu.ime_flag);
+        assert_eq!(cpu.sp, 0xDFEE);
+        assert_eq!(cpu.bus.peek_byte(0xDFEE), 0x02);
+        assert_eq!(cpu.bus.peek_byte(0xDFEF), 0x00);
+        assert_eq!(cpu.bus.peek_byte(0xFF0F) & 0x1F, 0x00);
+
+        // EI の直後の DI で取り消される
+        let mut cpu = newCPU();
+        cpu.ime_flag = false;
+        cpu.bus.poke_byte(0xFFFF, 0x01);
+        cpu.bus.poke_byte(0xFF0F, 0x01);
+        cpu.bus.poke_byte(0x0000, 0xFB); // EI
+        cpu.bus.poke_byte(0x0001, 0xF3); // DI
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0003);
+        assert!(!cpu.ime_flag);
+
+        // RETI はすぐに有効になる
+        let mut cpu = newCPU();
+        cpu.ime_flag = false;
+        cpu.sp = 0xDFF0;
+        cpu.bus.poke_byte(0xDFF0, 0x00);
+        cpu.bus.poke_byte(0xDFF1, 0x10);
+        cpu.bus.poke_byte(0xFFFF, 0x04);
+        cpu.bus.poke_byte(0xFF0F, 0x04);
+        cpu.bus.poke_byte(0x0000, 0xD9); // RETI
+        assert_eq!(cpu.step(), 16 + 20);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.bus.peek_byte(0xDFF1), 0x10);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut cpu = newCPU();
+        cpu.ime_flag = false;
+        cpu.bus.poke_byte(0xFFFF, 0x04);
+        cpu.bus.poke_byte(0x0000, 0x76); // HALT
+        cpu.bus.poke_byte(0x0001, 0x3C); // INC A
+        cpu.registers.a = 0;
+        cpu.step();
+        assert!(cpu.is_halted);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.pc, 0x0001);
+
+        // IME=0 なら割り込まずに起きるだけ
+        cpu.bus.poke_byte(0xFF0F, 0x04);
+        cpu.step();
+        assert!(!cpu.is_halted);
+        assert_eq!(cpu.pc, 0x0001);
+        cpu.step();
+        assert_eq!(cpu.registers.a, 1);
+        assert_eq!(cpu.pc, 0x0002);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        let mut cpu = newCPU();
+        cpu.ime_flag = false;
+        cpu.bus.poke_byte(0xFFFF, 0x04);
+        cpu.bus.poke_byte(0xFF0F, 0x04);
+        cpu.bus.poke_byte(0x0000, 0x76); // HALT
+        cpu.bus.poke_byte(0x0001, 0x3E); // LD A, d8
+        cpu.bus.poke_byte(0x0002, 0x14); // INC D
+        cpu.registers.d = 0;
+
+        // HALT せず、次の命令の読み込みで PC が進まない
+        cpu.step();
+        assert!(!cpu.is_halted);
+        assert_eq!(cpu.pc, 0x0001);
+        // LD A, 0x3E として実行される
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x3E);
+        assert_eq!(cpu.pc, 0x0002);
+        cpu.step();
+        assert_eq!(cpu.registers.d, 1);
+        assert_eq!(cpu.pc, 0x0003);
+    }
+
+    #[test]
+    fn test_ie_push() {
+        // PC の上位の push で IE が書き換わり、割り込みが取り消される
+        let mut cpu = newCPU();
+        cpu.pc = 0x0200;
+        cpu.sp = 0x0000;
+        cpu.bus.poke_byte(0x0200, 0x00);
+        cpu.bus.poke_byte(0xFFFF, 0x01);
+        cpu.bus.poke_byte(0xFF0F, 0x01);
+        assert_eq!(cpu.step(), 4 + 20);
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.bus.peek_byte(0xFFFF), 0x02);
+        assert_eq!(cpu.bus.peek_byte(0xFFFE), 0x01);
+        assert_eq!(cpu.bus.peek_byte(0xFF0F) & 0x1F, 0x01);
+
+        // 書き換わった IE で別の割り込みになる
+        let mut cpu = newCPU();
+        cpu.pc = 0x0200;
+        cpu.sp = 0x0000;
+        cpu.bus.poke_byte(0x0200, 0x00);
+        cpu.bus.poke_byte(0xFFFF, 0x01);
+        cpu.bus.poke_byte(0xFF0F, 0x03);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0048);
+        assert_eq!(cpu.bus.peek_byte(0xFF0F) & 0x1F, 0x01);
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        for opcode in [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ] {
+            let mut cpu = newCPU();
+            cpu.pc = 0x0200;
+            cpu.bus.poke_byte(0x0200, opcode);
+            assert_eq!(cpu.step(), 4);
+            assert!(cpu.is_locked);
+            assert_eq!(cpu.pc, 0x0200);
+            assert_eq!(cpu.take_illegal_opcode(), Some((0x0200, opcode)));
+            assert_eq!(cpu.take_illegal_opcode(), None);
+        }
+
+        // 止まったまま割り込みも受け付けないが、タイマーは進む
+        let mut cpu = newCPU();
+        cpu.bus.poke_byte(0x0000, 0xDD);
+        cpu.bus.poke_byte(0xFFFF, 0x01);
+        cpu.step();
+        cpu.bus.poke_byte(0xFF0F, 0x01);
+        let div = cpu.bus.peek_byte(0xFF04);
+        for _ in 0..64 {
+            assert_eq!(cpu.step(), 4);
+        }
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.bus.peek_byte(0xFF04), div.wrapping_add(1));
+        assert_eq!(cpu.take_illegal_opcode(), Some((0x0000, 0xDD)));
+    }
 }
@@ -0,0 +1,271 @@
+// デバッガーのコンソール
+//
+// 1行のコマンドを実行して、表示する文字列を返す。入力の読み方はフロントエンドに任せる。
+// アドレスは16進数、値は10進数 (0x を付けると16進数)。
+
+use crate::cheat::Cheat;
+use crate::ram_search::{Filter, RamSearch, Width};
+use crate::Emulator;
+
+const HELP: &str = "commands:
+    search start [8|16]    start a RAM search over WRAM, HRAM and cartridge RAM (default: 8 bit)
+    search equal           keep values that did not change since the last search
+    search changed         keep values that changed
+    search increased       keep values that increased
+    search decreased       keep values that decreased
+    search value <N>       keep values equal to N
+    search list [N]        show the first N results (default: 20)
+    cheat <INDEX> <VALUE>  add a GameShark cheat that keeps result INDEX at VALUE
+    peek <ADDR>            read a byte
+    poke <ADDR> <VALUE>    write a byte
+    trace <FILE>           write a gameboy-doctor style log of every instruction to FILE
+    trace off              stop tracing
+    help                   show this help";
+
+// 一度に表示する検索結果の数
+const LIST_SIZE: usize = 20;
+
+pub struct Debugger {
+    search: Option<RamSearch>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger { search: None }
+    }
+
+    pub fn search(&self) -> Option<&RamSearch> {
+        self.search.as_ref()
+    }
+
+    pub fn execute(&mut self, emulator: &mut Emulator, line: &str) -> String {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let result = match args.as_slice() {
+            [] => Ok(String::new()),
+            ["help"] => Ok(HELP.to_string()),
+            ["search", args @ ..] => self.execute_search(emulator, args),
+            ["cheat", index, value] => self.add_cheat(emulator, index, value),
+            ["peek", address] => parse_address(address).map(|address| {
+                let value = emulator.cpu_mut().bus.peek_byte(address);
+                format!("{:04X}: {:02X} ({})", address, value, value)
+            }),
+            ["poke", address, value] => parse_address(address).and_then(|address| {
+                let value = parse_byte(value)?;
+                emulator.cpu_mut().bus.poke_byte(address, value);
+                Ok(format!("{:04X}: {:02X}", address, value))
+            }),
+            ["trace", "off"] => emulator
+                .stop_trace()
+                .map(|_| "trace stopped".to_string())
+                .map_err(|e| e.to_string()),
+            ["trace", _, ..] => {
+                // パスに空白が入っていてもいいように、残りを全部パスにする
+                let path = line.trim()["trace".len()..].trim_start();
+                emulator
+                    .start_trace(path)
+                    .map(|_| format!("tracing to {}", path))
+                    .map_err(|e| format!("{}: {}", path, e))
+            }
+            _ => Err(format!("unknown command: {} (try \"help\")", line.trim())),
+        };
+        result.unwrap_or_else(|message| format!("error: {}", message))
+    }
+
+    fn execute_search(&mut self, emulator: &Emulator, args: &[&str]) -> Result<String, String> {
+        let filter = match args {
+            ["start"] | ["start", "8"] => return Ok(self.start(emulator, Width::Byte)),
+            ["start", "16"] => return Ok(self.start(emulator, Width::Word)),
+            ["list"] => return self.list(emulator, LIST_SIZE),
+            ["list", count] => {
+                let count = count
+                    .parse()
+                    .map_err(|_| format!("invalid count: {}", count))?;
+                return self.list(emulator, count);
+            }
+            ["equal"] => Filter::Equal,
+            ["changed"] => Filter::Changed,
+            ["increased"] => Filter::Increased,
+            ["decreased"] => Filter::Decreased,
+            ["value", value] => Filter::Value(parse_value(value)?),
+            _ => {
+                return Err(
+                    "usage: search <start|equal|changed|increased|decreased|value|list>"
+                        .to_string(),
+                )
+            }
+        };
+        let search = self.search_mut()?;
+        search.filter(emulator, filter);
+        Ok(format!("{} results", search.len()))
+    }
+
+    fn start(&mut self, emulator: &Emulator, width: Width) -> String {
+        let search = RamSearch::new(emulator, width);
+        let message = format!("{} results", search.len());
+        self.search = Some(search);
+        message
+    }
+
+    fn list(&mut self, emulator: &Emulator, count: usize) -> Result<String, String> {
+        let search = self.search_mut()?;
+        let width = search.width();
+        let results = search.results(emulator);
+        let mut lines: Vec<String> = results
+            .iter()
+            .take(count)
+            .enumerate()
+            .map(|(i, (location, value))| match width {
+                Width::Byte => format!("{:>4}: {}  {:02X} ({})", i, location, value, value),
+                Width::Word => format!("{:>4}: {}  {:04X} ({})", i, location, value, value),
+            })
+            .collect();
+        if results.len() > count {
+            lines.push(format!("... {} more", results.len() - count));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    // 検索結果の場所に値を書き込み続けるチートを追加する
+    fn add_cheat(
+        &mut self,
+        emulator: &mut Emulator,
+        index: &str,
+        value: &str,
+    ) -> Result<String, String> {
+        let index: usize = index
+            .parse()
+            .map_err(|_| format!("invalid index: {}", index))?;
+        let value = parse_byte(value)?;
+        let location = *self
+            .search_mut()?
+            .candidates()
+            .get(index)
+            .ok_or_else(|| format!("no result {}", index))?;
+        let code = location
+            .game_shark(value)
+            .ok_or_else(|| format!("{} is not in WRAM", location))?;
+
+        let code = code.to_string();
+        let cheat = Cheat::new(&location.to_string(), &code).map_err(|e| e.to_string())?;
+        emulator.add_cheat(cheat);
+        Ok(format!("cheat {}: {}", emulator.cheats().len(), code))
+    }
+
+    fn search_mut(&mut self) -> Result<&mut RamSearch, String> {
+        self.search
+            .as_mut()
+            .ok_or_else(|| "no search in progress (use \"search start\")".to_string())
+    }
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let hex = text.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(hex, 16).map_err(|_| format!("invalid address: {}", text))
+}
+
+fn parse_value(text: &str) -> Result<u16, String> {
+    let result = match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    result.map_err(|_| format!("invalid value: {}", text))
+}
+
+fn parse_byte(text: &str) -> Result<u8, String> {
+    let value = parse_value(text)?;
+    u8::try_from(value).map_err(|_| format!("invalid value: {}", text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::tempDir;
+    use crate::newEmulator;
+
+    #[test]
+    fn test_search() {
+        let mut emulator = newEmulator();
+        let mut debugger = Debugger::new();
+        assert!(debugger
+            .execute(&mut emulator, "search changed")
+            .starts_with("error:"));
+
+        debugger.execute(&mut emulator, "poke D123 99");
+        assert_eq!(
+            debugger.execute(&mut emulator, "search start"),
+            "32895 results"
+        );
+        assert_eq!(
+            debugger.execute(&mut emulator, "search value 99"),
+            "1 results"
+        );
+        debugger.execute(&mut emulator, "poke D123 0x62");
+        assert_eq!(
+            debugger.execute(&mut emulator, "search decreased"),
+            "1 results"
+        );
+        assert_eq!(
+            debugger.execute(&mut emulator, "search list"),
+            "   0: WRAM 1:D123  62 (98)"
+        );
+
+        assert_eq!(
+            debugger.execute(&mut emulator, "cheat 0 99"),
+            "cheat 1: 916323D1"
+        );
+        debugger.execute(&mut emulator, "poke D123 0");
+        emulator.run_frame();
+        assert_eq!(
+            debugger.execute(&mut emulator, "peek D123"),
+            "D123: 63 (99)"
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut emulator = newEmulator();
+        let mut debugger = Debugger::new();
+        let dir = tempDir("debugger trace");
+        let path = dir.join("trace log.txt");
+        let command = format!("trace {}", path.display());
+        assert_eq!(
+            debugger.execute(&mut emulator, &command),
+            format!("tracing to {}", path.display())
+        );
+        emulator.run_frame();
+        assert_eq!(
+            debugger.execute(&mut emulator, "trace off"),
+            "trace stopped"
+        );
+        assert!(!emulator.is_tracing());
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        let first = log.lines().next().unwrap();
+        assert!(first.starts_with("A:11 "));
+        assert!(first.ends_with(" SP:FFFE PC:0100 PCMEM:00,00,00,00"));
+        assert!(log.lines().count() > 1000);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut emulator = newEmulator();
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.execute(&mut emulator, ""), "");
+        assert!(debugger
+            .execute(&mut emulator, "jump")
+            .starts_with("error:"));
+        assert!(debugger
+            .execute(&mut emulator, "poke C000 256")
+            .starts_with("error:"));
+        assert!(debugger
+            .execute(&mut emulator, "peek XYZ")
+            .starts_with("error:"));
+    }
+}
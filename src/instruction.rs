@@ -7852,7 +7852,7 @@ pub fn instruction_cycles(byte: u8, prefiexed: bool) -> u16 {
             0x43 => 8,
             0x44 => 8,
             0x45 => 8,
-            0x46 => 16,
+            0x46 => 12,
             0x47 => 8,
             0x48 => 8,
             0x49 => 8,
@@ -7860,7 +7860,7 @@ pub fn instruction_cycles(byte: u8, prefiexed: bool) -> u16 {
             0x4B => 8,
             0x4C => 8,
             0x4D => 8,
-            0x4E => 16,
+            0x4E => 12,
             0x4F => 8,
             0x50 => 8,
             0x51 => 8,
@@ -7868,7 +7868,7 @@ pub fn instruction_cycles(byte: u8, prefiexed: bool) -> u16 {
             0x53 => 8,
             0x54 => 8,
             0x55 => 8,
-            0x56 => 16,
+            0x56 => 12,
             0x57 => 8,
             0x58 => 8,
             0x59 => 8,
@@ -7876,7 +7876,7 @@ pub fn instruction_cycles(byte: u8, prefiexed: bool) -> u16 {
             0x5B => 8,
             0x5C => 8,
             0x5D => 8,
-            0x5E => 16,
+            0x5E => 12,
             0x5F => 8,
             0x60 => 8,
             0x61 => 8,
@@ -7884,7 +7884,7 @@ pub fn instruction_cycles(byte: u8, prefiexed: bool) -> u16 {
             0x63 => 8,
             0x64 => 8,
             0x65 => 8,
-            0x66 => 16,
+            0x66 => 12,
             0x67 => 8,
             0x68 => 8,
             0x69 => 8,
@@ -7892,7 +7892,7 @@ pub fn instruction_cycles(byte: u8, prefiexed: bool) -> u16 {
             0x6B => 8,
             0x6C => 8,
             0x6D => 8,
-            0x6E => 16,
+            0x6E => 12,
             0x6F => 8,
             0x70 => 8,
             0x71 => 8,
@@ -7900,7 +7900,7 @@ pub fn instruction_cycles(byte: u8, prefiexed: bool) -> u16 {
             0x73 => 8,
             0x74 => 8,
             0x75 => 8,
-            0x76 => 16,
+            0x76 => 12,
             0x77 => 8,
             0x78 => 8,
             0x79 => 8,
@@ -7908,7 +7908,7 @@ pub fn instruction_cycles(byte: u8, prefiexed: bool) -> u16 {
             0x7B => 8,
             0x7C => 8,
             0x7D => 8,
-            0x7E => 16,
+            0x7E => 12,
             0x7F => 8,
             0x80 => 8,
             0x81 => 8,
@@ -1,3 +1,17 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub start: bool,
+    pub select: bool,
+}
+
 pub struct Joypad {
     pub left: bool,
     pub right: bool,
@@ -10,6 +24,9 @@ pub struct Joypad {
 
     pub select_direction: bool,
     pub select_action: bool,
+
+    // P10-P13 のどれかが High から Low になった (ジョイパッド割り込み)
+    interrupt: bool,
 }
 
 impl Joypad {
@@ -26,27 +43,84 @@ impl Joypad {
 
             select_direction: true,
             select_action: true,
+
+            interrupt: false,
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        let lines = self.lines();
+        self.left = buttons.left;
+        self.right = buttons.right;
+        self.up = buttons.up;
+        self.down = buttons.down;
+        self.a = buttons.a;
+        self.b = buttons.b;
+        self.start = buttons.start;
+        self.select = buttons.select;
+        self.update_interrupt(lines);
+    }
+
+    // 割り込みを要求していれば true を返し、要求を消す
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    // P10-P13 (選択されているボタンが押されていると 0)
+    pub fn lines(&self) -> u8 {
+        self.read() & 0x0F
+    }
+
+    fn update_interrupt(&mut self, before: u8) {
+        if before & !self.lines() != 0 {
+            self.interrupt = true;
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.left);
+        w.write_bool(self.right);
+        w.write_bool(self.up);
+        w.write_bool(self.down);
+        w.write_bool(self.a);
+        w.write_bool(self.b);
+        w.write_bool(self.start);
+        w.write_bool(self.select);
+        w.write_bool(self.select_direction);
+        w.write_bool(self.select_action);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.left = r.read_bool()?;
+        self.right = r.read_bool()?;
+        self.up = r.read_bool()?;
+        self.down = r.read_bool()?;
+        self.a = r.read_bool()?;
+        self.b = r.read_bool()?;
+        self.start = r.read_bool()?;
+        self.select = r.read_bool()?;
+        self.select_direction = r.read_bool()?;
+        self.select_action = r.read_bool()?;
+        Ok(())
+    }
+
+    // 押されているボタンを選択した場合も Low になるので割り込みが起きる
     pub fn write(&mut self, value: u8) {
+        let lines = self.lines();
         self.select_direction = (value & 0x10) == 0;
         self.select_action = (value & 0x20) == 0;
+        self.update_interrupt(lines);
     }
 
+    // 両方選択されている場合は、どちらかのボタンが押されているラインが Low になる
     pub fn read(&self) -> u8 {
         let mut value = 0xC0
             | if self.select_direction { 0x00 } else { 0x10 }
-            | if self.select_action { 0x00 } else { 0x20 };
-
-        if (self.select_direction && self.select_action)
-            | (!self.select_direction && !self.select_action)
-        {
-            return value | 0x0F;
-        }
+            | if self.select_action { 0x00 } else { 0x20 }
+            | 0x0F;
 
         if self.select_direction {
-            value = value
+            value &= 0xF0
                 | if self.right { 0x00 } else { 0x01 }
                 | if self.left { 0x00 } else { 0x02 }
                 | if self.up { 0x00 } else { 0x04 }
@@ -54,7 +128,7 @@ impl Joypad {
         }
 
         if self.select_action {
-            value = value
+            value &= 0xF0
                 | if self.a { 0x00 } else { 0x01 }
                 | if self.b { 0x00 } else { 0x02 }
                 | if self.select { 0x00 } else { 0x04 }
@@ -0,0 +1,247 @@
+pub mod apu;
+pub mod cartridge;
+pub mod cheat;
+pub mod cpu;
+pub mod debugger;
+pub mod instruction;
+pub mod joypad;
+pub mod mapper;
+pub mod memory_bus;
+pub mod ppu;
+pub mod ram_search;
+pub mod rewind;
+pub mod savestate;
+pub mod timer;
+pub mod trace;
+
+use cartridge::Cartridge;
+use cheat::{Cheat, Cheats};
+use cpu::{HardwareMode, CPU};
+use joypad::Buttons;
+use ppu::PPU;
+use savestate::{SaveStateError, StateReader, StateWriter};
+use std::path::Path;
+use trace::Tracer;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// 1フレーム (154ライン * 456サイクル) 分のサイクル数
+const CYCLES_PER_FRAME: u32 = 154 * 456;
+
+// フロントエンドに通知するイベント
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EmulatorEvent {
+    // 振動カートリッジのモーターの ON / OFF
+    Rumble(bool),
+    // 不正な命令を実行して CPU が止まった
+    IllegalOpcode { pc: u16, opcode: u8 },
+    // トレースの書き込みに失敗したので、トレースをやめた
+    TraceFailed(std::io::ErrorKind),
+}
+
+// SDLなどのフロントエンドから使うための窓口。
+// CPU, MemoryBus, PPU, APU, Cartridge をまとめて持つ。
+pub struct Emulator {
+    cpu: CPU,
+    mode: HardwareMode,
+    events: Vec<EmulatorEvent>,
+    rumble: bool,
+    cheats: Cheats,
+}
+
+impl Emulator {
+    pub fn new(cartridge: Cartridge, mode: HardwareMode, sample_rate: i32) -> Self {
+        Emulator {
+            cpu: CPU::new(cartridge, sample_rate, mode),
+            mode,
+            events: Vec::new(),
+            rumble: false,
+            cheats: Cheats::new(),
+        }
+    }
+
+    // 1フレーム分 (VBLANKに入るまで) 実行する。
+    // LCDがOFFの場合は、1フレーム分のサイクルを実行したら戻る。
+    pub fn run_frame(&mut self) {
+        let limit = CYCLES_PER_FRAME * if self.cpu.high_speed_mode() { 2 } else { 1 };
+        let mut cycles = 0;
+        while !self.cpu.bus.ppu.frame_updated && cycles < limit {
+            cycles += self.cpu.step() as u32;
+        }
+        if let Some((pc, opcode)) = self.cpu.take_illegal_opcode() {
+            self.events
+                .push(EmulatorEvent::IllegalOpcode { pc, opcode });
+        }
+        if let Some(kind) = self.cpu.take_trace_error() {
+            self.events.push(EmulatorEvent::TraceFailed(kind));
+        }
+        self.cpu.bus.ppu.frame_updated = false;
+        self.cpu.bus.cartridge.tick_frame();
+
+        // GameShark は VBLANK ごとにRAMを書き換える
+        for code in self.cheats.game_shark() {
+            self.cpu.bus.write_game_shark(&code);
+        }
+
+        // フレームの途中で一度でもモーターが回っていれば ON とする
+        if let Some(rumble) = self.cpu.bus.cartridge.take_rumble() {
+            if rumble != self.rumble {
+                self.rumble = rumble;
+                self.events.push(EmulatorEvent::Rumble(rumble));
+            }
+        }
+    }
+
+    // 前回呼び出し以降に起きたイベント
+    pub fn take_events(&mut self) -> Vec<EmulatorEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // RGB24 (160x144)
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.cpu.bus.ppu.frame
+    }
+
+    // 前回呼び出し以降に生成されたサンプル (f32, stereo)
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    // シリアルポート (0xFF01) に送信されたデータ
+    pub fn serial_output(&self) -> &[u8] {
+        &self.cpu.bus.serial
+    }
+
+    pub fn read_memory(&mut self, address: u16) -> u8 {
+        self.cpu.bus.peek_byte(address)
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.cpu.bus.set_buttons(buttons);
+    }
+
+    // 命令ごとのトレースをファイルに書く (gameboy-doctor の形式)
+    pub fn start_trace<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        self.cpu.set_tracer(Some(Tracer::create(path)?));
+        Ok(())
+    }
+
+    // バッファに残っている分も書き出す。process::exit の前には必ず呼ぶ (Drop が呼ばれない)
+    pub fn stop_trace(&mut self) -> std::io::Result<()> {
+        match self.cpu.set_tracer(None) {
+            Some(mut tracer) => tracer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.cpu.is_tracing()
+    }
+
+    pub fn save_ram(&mut self) -> std::io::Result<()> {
+        self.cpu.bus.cartridge.save_ram()
+    }
+
+    // 外部RAMに書き込みがあった場合だけセーブする。セーブしたら true
+    pub fn save_ram_if_dirty(&mut self) -> std::io::Result<bool> {
+        if !self.cpu.bus.cartridge.is_dirty() {
+            return Ok(false);
+        }
+        self.save_ram()?;
+        Ok(true)
+    }
+
+    // 現在の状態をバイト列にする
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(&self.cpu.bus.cartridge.rom_id());
+        self.cpu.save_state(&mut w);
+        self.cheats.save_state(&mut w);
+        w.into_bytes()
+    }
+
+    // save_state で作ったバイト列から状態を復元する。
+    // 失敗した場合は、読み込む前の状態に戻す。
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let rom_id = self.cpu.bus.cartridge.rom_id();
+        let mut r = StateReader::new(data, &rom_id)?;
+        let backup = self.save_state();
+        let result = self
+            .cpu
+            .load_state(&mut r)
+            .and_then(|_| self.cheats.load_state(&mut r))
+            .and_then(|_| r.finish());
+        if result.is_err() {
+            let mut r = StateReader::new(&backup, &rom_id).unwrap();
+            self.cpu.load_state(&mut r).unwrap();
+            self.cheats.load_state(&mut r).unwrap();
+        }
+        self.update_game_genie();
+        result
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    // 今のチートを置き換える
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        self.cheats = cheats;
+        self.update_game_genie();
+    }
+
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+        self.update_game_genie();
+    }
+
+    pub fn remove_cheat(&mut self, index: usize) -> Option<Cheat> {
+        let cheat = self.cheats.remove(index);
+        self.update_game_genie();
+        cheat
+    }
+
+    // 範囲外の場合は false
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        let result = self.cheats.set_enabled(index, enabled);
+        self.update_game_genie();
+        result
+    }
+
+    fn update_game_genie(&mut self) {
+        self.cpu
+            .bus
+            .cartridge
+            .set_game_genie(self.cheats.game_genie());
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cpu.bus.cartridge
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cpu.bus.cartridge
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.cpu.bus.ppu
+    }
+
+    pub fn hardware_mode(&self) -> HardwareMode {
+        self.mode
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+}
+
+// テスト用。テストROMの代わりに命令を直接書き込める Emulator
+#[cfg(test)]
+pub(crate) fn newEmulator() -> Emulator {
+    Emulator::new(Cartridge::for_test(), HardwareMode::CGB, 44100)
+}
@@ -1,29 +1,43 @@
-mod apu;
-mod cartridge;
-mod cpu;
-mod instruction;
-mod joypad;
-mod mapper;
-mod memory_bus;
-mod ppu;
-
-use std::time::Instant;
-
-use cartridge::Cartridge;
-use cpu::CPU;
-use joypad::Joypad;
+mod options;
+
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+use gameboy_emulator::cartridge::{Cartridge, CartridgeError, LoadOptions, SaveMismatch};
+use gameboy_emulator::cheat::Cheats;
+use gameboy_emulator::debugger::Debugger;
+use gameboy_emulator::joypad::Buttons;
+use gameboy_emulator::rewind::Rewind;
+use gameboy_emulator::{Emulator, EmulatorEvent, SCREEN_WIDTH};
+use options::Options;
 use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::{Event, WindowEvent};
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::messagebox::{self, ButtonData, ClickedButton, MessageBoxButtonFlag, MessageBoxFlag};
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::video::Window;
 use sdl2::EventPump;
 
 fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", options::USAGE);
+            return;
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let scale = 3;
+    let scale = options.scale;
     let window = video_subsystem
-        .window("GameBoy Emulator", 160 * scale as u32, 144 * scale as u32)
+        .window("GameBoy Emulator", 160 * scale, 144 * scale)
         .position_centered()
         .opengl()
         .build()
@@ -77,8 +91,10 @@ fn main() {
         .create_texture_target(PixelFormatEnum::RGB24, 256, 256)
         .unwrap();
 
-    bg1_canvas.window_mut().hide();
-    bg2_canvas.window_mut().hide();
+    if !options.debug_windows {
+        bg1_canvas.window_mut().hide();
+        bg2_canvas.window_mut().hide();
+    }
 
     // init audio
     let audio_subsystem = sdl_context.audio().unwrap();
@@ -92,83 +108,218 @@ fn main() {
     let device: AudioQueue<f32> = audio_subsystem
         .open_queue::<f32, _>(None, &desired_spec)
         .unwrap();
-    //
-
-    // let target_bytes = device.spec().freq * device.spec().channels as i32 * 2; // 秒
-    // let period_left = device.spec().freq / 256;
-    // let period_right = device.spec().freq / 512;
-    // let mut wave = Vec::new();
-    // for x in 0..target_bytes / 2 {
-    //     wave.push(if (x / period_left) % 2 == 0 {
-    //         0.02
-    //     } else {
-    //         -0.02
-    //     });
-    //     wave.push(if (x / period_right) % 2 == 0 {
-    //         0.02
-    //     } else {
-    //         -0.02
-    //     });
-    // }
-    // device.queue_audio(&wave).unwrap();
-    // println!("TB: {}, SIZE: {}", target_bytes, device.size() / 4);
-
-    device.resume();
-
-    let dq = "rom/GB/ROM/DQ_MONSTERS/31/Dragon Quest Monsters - Terry no Wonderland (Japan) (SGB Enhanced) (GB Compatible).gbc";
-    let kaeru = "rom/GB/ROM/KAERUNOTAMENI/35/Kaeru no Tame ni Kane wa Naru (Japan).gb";
-    let kinka = "rom/GB/ROM/MARIOLAND2/34/Super Mario Land 2 - 6-tsu no Kinka (Japan) (Rev 2).gb";
-    let pokemon =
-        "rom/GB/ROM/POKEMON GREEN/-1/Pocket Monsters - Midori (Japan) (Rev 1) (SGB Enhanced).gb";
-    let mario = "rom/GB/ROM/SUPER MARIOLAND/32/Super Mario Land (World).gb";
-    let zelda = "rom/GB/ROM/ZELDA/33/Zelda no Densetsu - Yume o Miru Shima (Japan).gb";
-    let zeldadx = "/Users/pluswing/develop/gameboy_emulator/rom/GB/ROM/ZELDA/45/Zelda no Densetsu - Yume o Miru Shima DX (Japan) (SGB Enhanced) (GB Compatible).gbc";
-    let yugioh = "rom/GB/ROM/YUGIOU/30/Yu-Gi-Oh! Duel Monsters (Japan) (SGB Enhanced).gb";
-    let dmg = "rom/dmg-acid2.gb";
-    let bm = "rom/GB/ROM/BEAT MANIA2/40/Beatmania GB 2 - Gotcha Mix (Japan) (SGB Enhanced) (GB Compatible).gbc";
-    let gold = "rom/GB/ROM/POKEMON_GLD/38/Pocket Monsters Kin (Japan) (Rev 1) (SGB Enhanced) (GB Compatible).gbc";
-    let yugi3 =
-        "rom/GB/ROM/YUGIOUDM3/41/Yu-Gi-Oh! Duel Monsters III - Tri Holy God Advant (Japan).gbc";
-    let yugi4 = "rom/GB/ROM/YUGIOUDM4J/43/Yu-Gi-Oh! Duel Monsters 4 - Battle of Great Duelist - Jounouchi Deck (Japan).gbc";
-
-    let cartridge = Cartridge::new(gold);
-    let mut cpu = CPU::new(cartridge, device);
+
+    if !options.mute {
+        device.resume();
+    }
+
+    // 振動カートリッジ用。最初に見つかったゲームコントローラーを振動させる
+    // ゲームコントローラーが使えない環境では振動しないだけ
+    let controller_subsystem = sdl_context.game_controller().ok();
+    let mut controller = controller_subsystem.as_ref().and_then(|subsystem| {
+        (0..subsystem.num_joysticks().unwrap_or(0))
+            .find(|&index| subsystem.is_game_controller(index))
+            .and_then(|index| subsystem.open(index).ok())
+    });
+
+    let cartridge = match load_cartridge(&options, canvas.window()) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            let message = format!("failed to load ROM: {}", e);
+            eprintln!("{}", message);
+            messagebox::show_simple_message_box(
+                MessageBoxFlag::ERROR,
+                "GameBoy Emulator",
+                &message,
+                canvas.window(),
+            )
+            .ok();
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = cartridge.patch_path() {
+        println!("patch applied: {}", path.display());
+    }
+    let mut emulator = Emulator::new(cartridge, options.mode, device.spec().freq);
+    if let Some(path) = &options.import_sav {
+        if let Err(e) = emulator.cartridge_mut().import_sav(path) {
+            eprintln!("failed to import save file: {}", e);
+            std::process::exit(1);
+        }
+    }
+    load_cheats(&mut emulator, &options);
+    let mut buttons = Buttons::default();
+    let mut rewind = Rewind::new(options.rewind_interval, options.rewind_buffer);
+    let mut rewinding = false;
+    let mut debugger = Debugger::new();
+    let commands = if options.debugger {
+        Some(read_commands())
+    } else {
+        None
+    };
+
+    let freq = device.spec().freq;
+    let max_buffer_size = freq / 60 * 6; // 6フレーム
 
     let mut timer = Instant::now();
+    let mut autosave_timer = Instant::now();
     let interval = 1_000_000_000 / 60; // 60FPS
     loop {
-        cpu.step();
-        if cpu.bus.ppu.frame_updated {
-            handle_user_input(&mut event_pump, &mut cpu.bus.cartridge, &mut cpu.bus.joypad);
-            cpu.bus.ppu.frame_updated = false;
-            let screen_state = cpu.bus.ppu.frame;
-            texture.update(None, &screen_state, 160 * 3).unwrap();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
+        // 巻き戻し中は、記録したステートを1フレームに1つずつ戻す
+        if rewinding {
+            rewind.rewind(&mut emulator);
+        } else {
+            emulator.run_frame();
+            rewind.record(&emulator);
+        }
+        for event in emulator.take_events() {
+            match event {
+                EmulatorEvent::Rumble(on) => {
+                    if let Some(controller) = &mut controller {
+                        let strength = if on { 0xFFFF } else { 0 };
+                        controller.set_rumble(strength, strength, 10_000).ok();
+                    }
+                }
+                EmulatorEvent::IllegalOpcode { pc, opcode } => {
+                    eprintln!("illegal opcode {:02X} at {:04X}: CPU locked up", opcode, pc)
+                }
+                EmulatorEvent::TraceFailed(kind) => {
+                    eprintln!("failed to write trace: {}", kind)
+                }
+            }
+        }
+        handle_user_input(
+            &mut event_pump,
+            &mut emulator,
+            &mut buttons,
+            &mut rewinding,
+            &options,
+        );
+        emulator.set_buttons(buttons);
+        if let Some(commands) = &commands {
+            for command in commands.try_iter() {
+                println!("{}", debugger.execute(&mut emulator, &command));
+            }
+        }
 
+        texture
+            .update(None, emulator.framebuffer(), SCREEN_WIDTH * 3)
+            .unwrap();
+        canvas.copy(&texture, None, None).unwrap();
+        canvas.present();
+
+        if options.debug_windows {
             bg1_texture
-                .update(None, cpu.bus.ppu.bg1.as_ref(), 256 * 3)
+                .update(None, emulator.ppu().bg1.as_ref(), 256 * 3)
                 .unwrap();
             bg1_canvas.copy(&bg1_texture, None, None).unwrap();
             bg1_canvas.present();
 
             bg2_texture
-                .update(None, cpu.bus.ppu.bg2.as_ref(), 256 * 3)
+                .update(None, emulator.ppu().bg2.as_ref(), 256 * 3)
                 .unwrap();
             bg2_canvas.copy(&bg2_texture, None, None).unwrap();
             bg2_canvas.present();
+        }
 
-            let time = timer.elapsed().as_nanos();
-            if time < interval {
-                ::std::thread::sleep(std::time::Duration::new(0, (interval - time) as u32));
+        let wave = emulator.audio_samples();
+        let curret_buffer_size = device.size() as i32 / 4 / 2; // f32=4byte, 2 channle
+        if !options.mute && curret_buffer_size < max_buffer_size {
+            device.queue_audio(&wave).unwrap();
+        }
+
+        // 落ちてもセーブが消えないように、書き込みがあったら定期的にセーブする
+        if options.autosave > 0 && autosave_timer.elapsed() >= Duration::from_secs(options.autosave)
+        {
+            autosave_timer = Instant::now();
+            if let Err(e) = emulator.save_ram_if_dirty() {
+                eprintln!("failed to save: {}", e);
             }
-            timer = Instant::now();
-            // ::std::thread::sleep(std::time::Duration::new(0, 70_000));
         }
+
+        let time = timer.elapsed().as_nanos();
+        if time < interval {
+            ::std::thread::sleep(std::time::Duration::new(0, (interval - time) as u32));
+        }
+        timer = Instant::now();
     }
 }
 
-fn handle_user_input(event_pump: &mut EventPump, cartridge: &mut Cartridge, joypad: &mut Joypad) {
+// ゲームを止めないように、端末の入力は別スレッドで読む
+fn read_commands() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// セーブファイルの大きさが合わない場合は、どうするかダイアログで聞く
+fn load_cartridge(options: &Options, window: &Window) -> Result<Cartridge, CartridgeError> {
+    let mut load_options = LoadOptions {
+        save_dir: options.save_dir.clone(),
+        archive_entry: options.entry.clone(),
+        patch: options.patch.clone(),
+        save_mismatch: options.save_mismatch.unwrap_or(SaveMismatch::Fail),
+        ..LoadOptions::default()
+    };
+    match Cartridge::load_with(&options.rom, &load_options) {
+        Err(e @ CartridgeError::SaveSizeMismatch { .. }) if options.save_mismatch.is_none() => {
+            let buttons = [
+                ButtonData {
+                    flags: MessageBoxButtonFlag::RETURNKEY_DEFAULT,
+                    button_id: 0,
+                    text: "Back up",
+                },
+                ButtonData {
+                    flags: MessageBoxButtonFlag::NOTHING,
+                    button_id: 1,
+                    text: "Resize",
+                },
+                ButtonData {
+                    flags: MessageBoxButtonFlag::ESCAPEKEY_DEFAULT,
+                    button_id: 2,
+                    text: "Quit",
+                },
+            ];
+            let message = format!(
+                "{}\n\nBack up: rename the save file and start with empty RAM\nResize: load as much as fits",
+                e
+            );
+            let clicked = messagebox::show_message_box(
+                MessageBoxFlag::WARNING,
+                &buttons,
+                "GameBoy Emulator",
+                &message,
+                window,
+                None,
+            );
+            load_options.save_mismatch = match clicked {
+                Ok(ClickedButton::CustomButton(ButtonData { button_id: 0, .. })) => {
+                    SaveMismatch::Backup
+                }
+                Ok(ClickedButton::CustomButton(ButtonData { button_id: 1, .. })) => {
+                    SaveMismatch::Resize
+                }
+                _ => return Err(e),
+            };
+            Cartridge::load_with(&options.rom, &load_options)
+        }
+        result => result,
+    }
+}
+
+fn handle_user_input(
+    event_pump: &mut EventPump,
+    emulator: &mut Emulator,
+    joypad: &mut Buttons,
+    rewinding: &mut bool,
+    options: &Options,
+) {
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
@@ -180,10 +331,61 @@ fn handle_user_input(event_pump: &mut EventPump, cartridge: &mut Cartridge, joyp
                 keycode: Some(Keycode::Escape),
                 ..
             } => {
-                cartridge.save_ram();
+                if let Err(e) = emulator.save_ram() {
+                    eprintln!("failed to save: {}", e);
+                }
+                if let Some(path) = &options.export_sav {
+                    match emulator.cartridge_mut().export_sav(path) {
+                        Ok(_) => println!("save file exported: {}", path.display()),
+                        Err(e) => {
+                            eprintln!("failed to export save file: {}: {}", path.display(), e)
+                        }
+                    }
+                }
+                // process::exit では Drop が呼ばれないので、トレースをここで書き出す
+                if let Err(e) = emulator.stop_trace() {
+                    eprintln!("failed to write trace: {}", e);
+                }
                 std::process::exit(0);
             }
 
+            // ステートセーブ (F1-F8) / ロード (Shift + F1-F8)
+            Event::KeyDown {
+                keycode: Some(keycode),
+                keymod,
+                repeat: false,
+                ..
+            } if state_slot(keycode).is_some() => {
+                let path = state_path(emulator, state_slot(keycode).unwrap());
+                if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                    load_state(emulator, &path);
+                } else {
+                    save_state(emulator, &path);
+                }
+            }
+
+            // チートの切り替え (Ctrl + 1-9)
+            Event::KeyDown {
+                keycode: Some(keycode),
+                keymod,
+                repeat: false,
+                ..
+            } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD)
+                && cheat_slot(keycode).is_some() =>
+            {
+                toggle_cheat(emulator, cheat_slot(keycode).unwrap());
+            }
+
+            // 押している間、巻き戻す
+            Event::KeyDown {
+                keycode: Some(Keycode::Backspace),
+                ..
+            } => *rewinding = true,
+            Event::KeyUp {
+                keycode: Some(Keycode::Backspace),
+                ..
+            } => *rewinding = false,
+
             // joypad
             Event::KeyDown {
                 keycode: Some(Keycode::A),
@@ -265,6 +467,99 @@ fn handle_user_input(event_pump: &mut EventPump, cartridge: &mut Cartridge, joyp
     }
 }
 
+fn state_slot(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        Keycode::F5 => Some(5),
+        Keycode::F6 => Some(6),
+        Keycode::F7 => Some(7),
+        Keycode::F8 => Some(8),
+        _ => None,
+    }
+}
+
+fn cheat_slot(keycode: Keycode) -> Option<usize> {
+    match keycode {
+        Keycode::Num1 => Some(0),
+        Keycode::Num2 => Some(1),
+        Keycode::Num3 => Some(2),
+        Keycode::Num4 => Some(3),
+        Keycode::Num5 => Some(4),
+        Keycode::Num6 => Some(5),
+        Keycode::Num7 => Some(6),
+        Keycode::Num8 => Some(7),
+        Keycode::Num9 => Some(8),
+        _ => None,
+    }
+}
+
+// --cheats がなければ、セーブファイルと同じ名前の .cht を探す
+fn load_cheats(emulator: &mut Emulator, options: &Options) {
+    let path = match &options.cheats {
+        Some(path) => path.clone(),
+        None => match emulator.cartridge().save_path() {
+            Some(path) if path.with_extension("cht").is_file() => path.with_extension("cht"),
+            _ => return,
+        },
+    };
+    match Cheats::load(&path) {
+        Ok(cheats) => {
+            println!("cheats loaded: {} ({})", path.display(), cheats.len());
+            for (i, cheat) in cheats.iter().enumerate() {
+                println!("  {}: {} [{}]", i + 1, cheat.name, on_off(cheat.enabled));
+            }
+            emulator.set_cheats(cheats);
+        }
+        Err(e) => eprintln!("failed to load cheats: {}", e),
+    }
+}
+
+fn toggle_cheat(emulator: &mut Emulator, index: usize) {
+    let enabled = match emulator.cheats().get(index) {
+        Some(cheat) => !cheat.enabled,
+        None => return,
+    };
+    emulator.set_cheat_enabled(index, enabled);
+    let cheat = emulator.cheats().get(index).unwrap();
+    println!("cheat {}: {} [{}]", index + 1, cheat.name, on_off(enabled));
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+// <セーブディレクトリ or ROMのディレクトリ>/<ROM名>.ss1
+// セーブファイルと同じ場所に、同じ名前で作る
+fn state_path(emulator: &Emulator, slot: u8) -> PathBuf {
+    let mut path = emulator.cartridge().save_path().unwrap().to_path_buf();
+    path.set_extension(format!("ss{}", slot));
+    path
+}
+
+fn save_state(emulator: &Emulator, path: &Path) {
+    match std::fs::write(path, emulator.save_state()) {
+        Ok(_) => println!("state saved: {}", path.display()),
+        Err(e) => eprintln!("failed to save state: {}: {}", path.display(), e),
+    }
+}
+
+fn load_state(emulator: &mut Emulator, path: &Path) {
+    let result = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| emulator.load_state(&data).map_err(|e| e.to_string()));
+    match result {
+        Ok(_) => println!("state loaded: {}", path.display()),
+        Err(e) => eprintln!("failed to load state: {}: {}", path.display(), e),
+    }
+}
+
 fn find_sdl_gl_driver() -> Option<u32> {
     for (index, item) in sdl2::render::drivers().enumerate() {
         if item.name == "opengl" {
@@ -1,33 +1,494 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use huc1::HuC1;
+use huc3::HuC3;
+use mbc1::MBC1;
+use mbc2::MBC2;
+use mbc3::MBC3;
+use mbc5::MBC5;
 use nombc::NoMBC;
 
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+pub mod huc1;
+pub mod huc3;
+pub mod infrared;
 pub mod mbc1;
+pub mod mbc2;
 pub mod mbc3;
 pub mod mbc5;
 pub mod nombc;
 
-pub enum Mapper {
-    NoMBC(nombc::NoMBC),
-    MBC1(mbc1::MBC1),
-    MBC3(mbc3::MBC3),
-    MBC5(mbc5::MBC5),
+// 0x0148 (ROMサイズ) からROMバンク (16KiB) の数を求める
+pub fn rom_banks(value: u8) -> Option<usize> {
+    match value {
+        0x00..=0x08 => Some(2 << value),
+        0x52 => Some(72),
+        0x53 => Some(80),
+        0x54 => Some(96),
+        _ => None,
+    }
+}
+
+// ROMのバンク数。ヘッダーのROMサイズが不正な場合は、ファイルのサイズから求める
+pub fn rom_bank_count(rom: &[u8]) -> usize {
+    rom.get(0x0148)
+        .and_then(|&value| rom_banks(value))
+        .unwrap_or_else(|| (rom.len() / 0x4000).max(2))
 }
 
-impl Mapper {
-    pub fn read_byte(&mut self, rom: &Vec<u8>, ram: &Vec<u8>, addr: u16) -> u8 {
-        match self {
-            Mapper::NoMBC(mapper) => mapper.read_byte(rom, ram, addr),
-            Mapper::MBC1(mapper) => mapper.read_byte(rom, ram, addr),
-            Mapper::MBC3(mapper) => mapper.read_byte(rom, ram, addr),
-            Mapper::MBC5(mapper) => mapper.read_byte(rom, ram, addr),
+// RTCの時刻 (UNIX time)
+fn now() -> u64 {
+    Utc::now().timestamp().max(0) as u64
+}
+
+// カートリッジのマッパー (MBC) が実装するもの
+// rom, ram は Cartridge が持っていて、アクセスの度に渡される。
+pub trait MapperImpl {
+    // 0x0000-0x7FFF, 0xA000-0xBFFF の読み込み
+    fn read_byte(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8;
+
+    // 0x0000-0x7FFF (レジスタ), 0xA000-0xBFFF の書き込み
+    // RAM か RTC に書き込んだ (セーブファイルが変わる) 場合は true
+    fn write_byte(&mut self, rom: &mut [u8], ram: &mut [u8], addr: u16, value: u8) -> bool;
+
+    // ステートセーブ (バンク番号などのレジスタ)
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), SaveStateError> {
+        Ok(())
+    }
+
+    // .save に書き出すデータ。RTCなどを持っている場合は、RAMの後ろに付け足す
+    fn export_battery(&mut self, ram: &[u8]) -> Vec<u8> {
+        ram.to_vec()
+    }
+
+    // .save から読み込む。形式が合わない場合は false
+    fn import_battery(&mut self, ram: &mut [u8], data: &[u8]) -> bool {
+        if data.len() != ram.len() {
+            return false;
+        }
+        ram.copy_from_slice(data);
+        true
+    }
+
+    // カートリッジに内蔵されているRAMのサイズ (0x0149 より優先する)
+    fn internal_ram_size(&self) -> Option<usize> {
+        None
+    }
+
+    // 1フレームごとに呼ばれる (RTCなど)
+    fn tick_frame(&mut self) {}
+
+    // 振動カートリッジの場合、前回呼んでからモーターが回ったか
+    fn take_rumble(&mut self) -> Option<bool> {
+        None
+    }
+}
+
+// ROMを受け取ってマッパーを作る
+pub type MapperFactory = fn(rom: &[u8]) -> Box<dyn MapperImpl>;
+
+// カートリッジタイプ (0x0147) からマッパーを作るためのテーブル。
+// default() で組み込みのマッパーが登録される。独自のマッパーは register で追加する。
+#[derive(Clone)]
+pub struct MapperRegistry {
+    factories: HashMap<u8, MapperFactory>,
+}
+
+impl MapperRegistry {
+    // 何も登録されていないレジストリ
+    pub fn new() -> Self {
+        MapperRegistry {
+            factories: HashMap::new(),
+        }
+    }
+
+    // 同じカートリッジタイプが登録済みの場合は上書きする
+    pub fn register(&mut self, cartridge_type: u8, factory: MapperFactory) {
+        self.factories.insert(cartridge_type, factory);
+    }
+
+    pub fn is_supported(&self, cartridge_type: u8) -> bool {
+        self.factories.contains_key(&cartridge_type)
+    }
+
+    // 未対応のカートリッジタイプの場合は None
+    pub fn create(&self, rom: &[u8]) -> Option<Box<dyn MapperImpl>> {
+        let cartridge_type = *rom.get(0x0147)?;
+        let factory = self.factories.get(&cartridge_type)?;
+        Some(factory(rom))
+    }
+}
+
+impl Default for MapperRegistry {
+    fn default() -> Self {
+        let mut registry = MapperRegistry::new();
+        registry.register(0x00, |_| Box::new(NoMBC::new()));
+        registry.register(0x01, |rom| Box::new(MBC1::new(rom_bank_count(rom))));
+        registry.register(0x02, |rom| Box::new(MBC1::new(rom_bank_count(rom)))); // + RAM
+        registry.register(0x03, |rom| Box::new(MBC1::new(rom_bank_count(rom)))); // + RAM + BATTERY
+        registry.register(0x05, |rom| Box::new(MBC2::new(rom_bank_count(rom))));
+        registry.register(0x06, |rom| Box::new(MBC2::new(rom_bank_count(rom)))); // + BATTERY
+        registry.register(0x08, |_| Box::new(NoMBC::new())); // + RAM
+        registry.register(0x09, |_| Box::new(NoMBC::new())); // + RAM + BATTERY
+        registry.register(0x0F, |rom| Box::new(MBC3::new(rom_bank_count(rom), true))); // TIMER + BATTERY
+        registry.register(0x10, |rom| Box::new(MBC3::new(rom_bank_count(rom), true))); // TIMER + RAM + BATTERY
+        registry.register(0x11, |rom| Box::new(MBC3::new(rom_bank_count(rom), false)));
+        registry.register(0x12, |rom| Box::new(MBC3::new(rom_bank_count(rom), false))); // + RAM
+        registry.register(0x13, |rom| Box::new(MBC3::new(rom_bank_count(rom), false))); // + RAM + BATTERY
+        registry.register(0x19, |rom| Box::new(MBC5::new(rom_bank_count(rom), false)));
+        registry.register(0x1A, |rom| Box::new(MBC5::new(rom_bank_count(rom), false))); // + RAM
+        registry.register(0x1B, |rom| Box::new(MBC5::new(rom_bank_count(rom), false))); // + RAM + BATTERY
+        registry.register(0x1C, |rom| Box::new(MBC5::new(rom_bank_count(rom), true))); // + RUMBLE
+        registry.register(0x1D, |rom| Box::new(MBC5::new(rom_bank_count(rom), true))); // + RUMBLE + RAM
+        registry.register(0x1E, |rom| Box::new(MBC5::new(rom_bank_count(rom), true))); // + RUMBLE + RAM + BATTERY
+        registry.register(0xFE, |rom| Box::new(HuC3::new(rom_bank_count(rom))));
+        registry.register(0xFF, |rom| Box::new(HuC1::new(rom_bank_count(rom)))); // + RAM + BATTERY
+        registry
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::savestate::RomId;
+
+    // 各バンクの先頭にバンク番号を書いたROM
+    fn rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom
+    }
+
+    // 全てのマッパーで守るべきこと
+    //   - ゲームから何を書き込まれても panic しない
+    //   - ROMは書き換わらない
+    //   - 無効になっている / 存在しない RAM は 0xFF が読めて、書き込みは無視される
+    struct Case {
+        name: &'static str,
+        mapper: Box<dyn MapperImpl>,
+        // RAMを有効 / 無効にする時に 0x0000 に書く値
+        enable: u8,
+        disable: Option<u8>,
+    }
+
+    fn cases(rom_banks: usize) -> Vec<Case> {
+        vec![
+            Case {
+                name: "NoMBC",
+                mapper: Box::new(nombc::NoMBC::new()),
+                enable: 0x0A,
+                disable: None,
+            },
+            Case {
+                name: "MBC1",
+                mapper: Box::new(mbc1::MBC1::new(rom_banks)),
+                enable: 0x0A,
+                disable: Some(0x00),
+            },
+            Case {
+                name: "MBC2",
+                mapper: Box::new(mbc2::MBC2::new(rom_banks)),
+                enable: 0x0A,
+                disable: Some(0x00),
+            },
+            Case {
+                name: "MBC3",
+                mapper: Box::new(mbc3::MBC3::new(rom_banks, true)),
+                enable: 0x0A,
+                disable: Some(0x00),
+            },
+            Case {
+                name: "MBC5",
+                mapper: Box::new(mbc5::MBC5::new(rom_banks, true)),
+                enable: 0x0A,
+                disable: Some(0x00),
+            },
+            Case {
+                name: "HuC1",
+                mapper: Box::new(huc1::HuC1::new(rom_banks)),
+                enable: 0x00,
+                disable: None,
+            },
+            Case {
+                name: "HuC3",
+                mapper: Box::new(huc3::HuC3::new(rom_banks)),
+                enable: 0x0A,
+                // RAMを無効にするモードはない (0x0 は読み込みのみ)
+                disable: None,
+            },
+        ]
+    }
+
+    const VALUES: [u8; 10] = [0x00, 0x01, 0x08, 0x0A, 0x0E, 0x0F, 0x3F, 0x7F, 0x80, 0xFF];
+
+    #[test]
+    fn test_conformance_rom_writes() {
+        for ram_size in [0, 0x800, 0x2000, 0x8000, 0x20000] {
+            for case in cases(4) {
+                let mut mapper = case.mapper;
+                let rom_before = rom(4);
+                let mut rom = rom_before.clone();
+                let mut ram = vec![0; ram_size];
+                for addr in (0x0000..0x8000).step_by(0x80) {
+                    for value in VALUES {
+                        mapper.write_byte(&mut rom, &mut ram, addr, value);
+                        mapper.write_byte(&mut rom, &mut ram, addr | 0x7F, value);
+                        for addr in [0x0000, 0x3FFF, 0x4000, 0x7FFF, 0xA000, 0xBFFF] {
+                            mapper.read_byte(&rom, &ram, addr);
+                        }
+                        mapper.write_byte(&mut rom, &mut ram, 0xA000, value);
+                        mapper.write_byte(&mut rom, &mut ram, 0xBFFF, value);
+                    }
+                }
+                assert!(rom == rom_before, "{}: ROM was modified", case.name);
+                assert_eq!(ram.len(), ram_size, "{}", case.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_conformance_disabled_ram() {
+        for case in cases(4) {
+            let Some(disable) = case.disable else {
+                continue;
+            };
+            let mut mapper = case.mapper;
+            let mut rom = rom(4);
+            let mut ram = vec![0x12; 0x8000];
+
+            // 電源を入れた直後と、無効にした後
+            for _ in 0..2 {
+                for addr in [0xA000, 0xA1FF, 0xBFFF] {
+                    mapper.write_byte(&mut rom, &mut ram, addr, 0x34);
+                    assert_eq!(mapper.read_byte(&rom, &ram, addr), 0xFF, "{}", case.name);
+                }
+                mapper.write_byte(&mut rom, &mut ram, 0x0000, case.enable);
+                mapper.write_byte(&mut rom, &mut ram, 0x0000, disable);
+            }
+            assert!(
+                ram.iter().all(|&b| b == 0x12),
+                "{}: RAM was modified",
+                case.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_conformance_absent_ram() {
+        for case in cases(4) {
+            let mut mapper = case.mapper;
+            let mut rom = rom(4);
+            let mut ram = vec![];
+            mapper.write_byte(&mut rom, &mut ram, 0x0000, case.enable);
+            for bank in 0..4 {
+                mapper.write_byte(&mut rom, &mut ram, 0x4000, bank);
+                for addr in [0xA000, 0xA1FF, 0xBFFF] {
+                    mapper.write_byte(&mut rom, &mut ram, addr, 0x34);
+                    assert_eq!(mapper.read_byte(&rom, &ram, addr), 0xFF, "{}", case.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_conformance_enabled_ram() {
+        for case in cases(4) {
+            let mut mapper = case.mapper;
+            let mut rom = rom(4);
+            // MBC2 は 512 x 4bit
+            let mut ram = vec![0; if case.name == "MBC2" { 0x200 } else { 0x2000 }];
+            mapper.write_byte(&mut rom, &mut ram, 0x0000, case.enable);
+            mapper.write_byte(&mut rom, &mut ram, 0x4000, 0x00);
+            mapper.write_byte(&mut rom, &mut ram, 0xA000, 0xF5);
+            mapper.write_byte(&mut rom, &mut ram, 0xA1FF, 0xFA);
+            assert_eq!(mapper.read_byte(&rom, &ram, 0xA000), 0xF5, "{}", case.name);
+            assert_eq!(mapper.read_byte(&rom, &ram, 0xA1FF), 0xFA, "{}", case.name);
         }
     }
 
-    pub fn write_byte(&mut self, rom: &mut Vec<u8>, ram: &mut Vec<u8>, addr: u16, value: u8) {
-        match self {
-            Mapper::NoMBC(mapper) => mapper.write_byte(rom, ram, addr, value),
-            Mapper::MBC1(mapper) => mapper.write_byte(rom, ram, addr, value),
-            Mapper::MBC3(mapper) => mapper.write_byte(rom, ram, addr, value),
-            Mapper::MBC5(mapper) => mapper.write_byte(rom, ram, addr, value),
+    #[test]
+    fn test_registry() {
+        let registry = MapperRegistry::default();
+        let mut rom = rom(4);
+        for cartridge_type in [0x00, 0x01, 0x05, 0x10, 0x13, 0x1B, 0x1E, 0xFE, 0xFF] {
+            rom[0x0147] = cartridge_type;
+            assert!(registry.create(&rom).is_some(), "{:02X}", cartridge_type);
+        }
+        rom[0x0147] = 0x20;
+        assert!(registry.create(&rom).is_none());
+        assert!(registry.create(&[]).is_none());
+
+        // 独自のマッパーを追加する
+        struct Fixed {}
+        impl MapperImpl for Fixed {
+            fn read_byte(&mut self, _rom: &[u8], _ram: &[u8], _addr: u16) -> u8 {
+                0x42
+            }
+            fn write_byte(
+                &mut self,
+                _rom: &mut [u8],
+                _ram: &mut [u8],
+                _addr: u16,
+                _value: u8,
+            ) -> bool {
+                false
+            }
+        }
+        let mut registry = MapperRegistry::default();
+        registry.register(0x20, |_| Box::new(Fixed {}));
+        assert!(registry.is_supported(0x20));
+        let mut mapper = registry.create(&rom).unwrap();
+        assert_eq!(mapper.read_byte(&rom, &[], 0x4000), 0x42);
+        let mut ram = vec![1, 2, 3];
+        assert!(mapper.import_battery(&mut ram, &[4, 5, 6]));
+        assert_eq!(mapper.export_battery(&ram), vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn test_rom_banks() {
+        assert_eq!(rom_banks(0x00), Some(2));
+        assert_eq!(rom_banks(0x06), Some(128));
+        assert_eq!(rom_banks(0x08), Some(512));
+        assert_eq!(rom_banks(0x52), Some(72));
+        assert_eq!(rom_banks(0x10), None);
+    }
+
+    #[test]
+    fn test_bank_wrap() {
+        let mut rom = rom(8);
+        let mut ram = vec![0; 0x2000];
+        let mappers: [Box<dyn MapperImpl>; 3] = [
+            Box::new(mbc1::MBC1::new(8)),
+            Box::new(mbc3::MBC3::new(8, false)),
+            Box::new(mbc5::MBC5::new(8, false)),
+        ];
+        for mut mapper in mappers {
+            mapper.write_byte(&mut rom, &mut ram, 0x2000, 0x0B);
+            assert_eq!(mapper.read_byte(&rom, &ram, 0x4000), 0x03);
+            mapper.write_byte(&mut rom, &mut ram, 0x2000, 0x1F);
+            assert_eq!(mapper.read_byte(&rom, &ram, 0x4000), 0x07);
+        }
+    }
+
+    #[test]
+    fn test_mbc3_large_rom() {
+        let mut rom = rom(128);
+        let mut ram = vec![0; 0x2000];
+        let mut mapper = Box::new(mbc3::MBC3::new(128, false));
+        mapper.write_byte(&mut rom, &mut ram, 0x2000, 0x7F);
+        assert_eq!(mapper.read_byte(&rom, &ram, 0x4000), 0x7F);
+        mapper.write_byte(&mut rom, &mut ram, 0x2000, 0x40);
+        assert_eq!(mapper.read_byte(&rom, &ram, 0x4000), 0x40);
+    }
+
+    #[test]
+    fn test_mbc30_ram_banks() {
+        let mut rom = rom(256);
+        let mut ram = vec![0; 0x10000];
+        let mut mapper = Box::new(mbc3::MBC3::new(256, false));
+        mapper.write_byte(&mut rom, &mut ram, 0x2000, 0xFF);
+        assert_eq!(mapper.read_byte(&rom, &ram, 0x4000), 0xFF);
+
+        mapper.write_byte(&mut rom, &mut ram, 0x0000, 0x0A);
+        for bank in 0..8 {
+            mapper.write_byte(&mut rom, &mut ram, 0x4000, bank);
+            mapper.write_byte(&mut rom, &mut ram, 0xA000, bank + 1);
+        }
+        for bank in 0..8 {
+            mapper.write_byte(&mut rom, &mut ram, 0x4000, bank);
+            assert_eq!(mapper.read_byte(&rom, &ram, 0xA000), bank + 1);
+        }
+    }
+
+    // ステートを保存して、別のマッパーに読み込む
+    fn copy_state(from: &dyn MapperImpl, to: &mut dyn MapperImpl) {
+        let id = RomId::from_rom(&[]);
+        let mut w = StateWriter::new(&id);
+        from.save_state(&mut w);
+        let data = w.into_bytes();
+        let mut r = StateReader::new(&data, &id).unwrap();
+        to.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+    }
+
+    #[test]
+    fn test_infrared_state() {
+        let mut rom = rom(8);
+        let mut ram = vec![0; 0x2000];
+
+        // HuC1: 0x0E で赤外線
+        let mut mapper = huc1::HuC1::new(8);
+        mapper.write_byte(&mut rom, &mut ram, 0x0000, 0x0E);
+        mapper.write_byte(&mut rom, &mut ram, 0xA000, 0x01);
+        mapper.infrared.set_light(true);
+        let mut loaded = huc1::HuC1::new(8);
+        copy_state(&mapper, &mut loaded);
+        assert!(loaded.infrared.led());
+        assert_eq!(loaded.read_byte(&rom, &ram, 0xA000), 0xC1);
+
+        // HuC3: モード 0xE で赤外線
+        let mut mapper = huc3::HuC3::new(8);
+        mapper.write_byte(&mut rom, &mut ram, 0x0000, 0x0E);
+        mapper.write_byte(&mut rom, &mut ram, 0xA000, 0x01);
+        mapper.infrared.set_light(true);
+        let mut loaded = huc3::HuC3::new(8);
+        copy_state(&mapper, &mut loaded);
+        assert!(loaded.infrared.led());
+        assert_eq!(loaded.read_byte(&rom, &ram, 0xA000), 0xC1);
+    }
+
+    #[test]
+    fn test_mbc5_rumble() {
+        let mut rom = rom(8);
+        let mut ram = vec![0; 0x2000 * 8];
+        let mut mapper = Box::new(mbc5::MBC5::new(8, true));
+        mapper.write_byte(&mut rom, &mut ram, 0x0000, 0x0A);
+        mapper.write_byte(&mut rom, &mut ram, 0x4000, 0x00);
+        mapper.write_byte(&mut rom, &mut ram, 0xA000, 0x12);
+
+        // bit3 はRAMバンクではなくモーター
+        mapper.write_byte(&mut rom, &mut ram, 0x4000, 0x08);
+        assert_eq!(mapper.read_byte(&rom, &ram, 0xA000), 0x12);
+        mapper.write_byte(&mut rom, &mut ram, 0x4000, 0x00);
+        assert_eq!(mapper.take_rumble(), Some(true));
+        assert_eq!(mapper.take_rumble(), Some(false));
+
+        // 回っている途中のステートを読み込むと、回ったまま
+        mapper.write_byte(&mut rom, &mut ram, 0x4000, 0x08);
+        let mut loaded = mbc5::MBC5::new(8, true);
+        copy_state(mapper.as_ref(), &mut loaded);
+        assert_eq!(loaded.take_rumble(), Some(true));
+        assert_eq!(loaded.take_rumble(), Some(true));
+
+        let mut mapper = Box::new(mbc5::MBC5::new(8, false));
+        mapper.write_byte(&mut rom, &mut ram, 0x4000, 0x08);
+        assert_eq!(mapper.take_rumble(), None);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        // ヘッダーより小さいROM、RAMなしでもpanicしない
+        let mut rom = vec![0; 0x8000];
+        let mut ram = vec![];
+        let mappers: [Box<dyn MapperImpl>; 4] = [
+            Box::new(nombc::NoMBC::new()),
+            Box::new(mbc1::MBC1::new(64)),
+            Box::new(mbc3::MBC3::new(128, false)),
+            Box::new(mbc5::MBC5::new(512, false)),
+        ];
+        for mut mapper in mappers {
+            mapper.write_byte(&mut rom, &mut ram, 0x0000, 0x0A);
+            mapper.write_byte(&mut rom, &mut ram, 0x2000, 0x3F);
+            mapper.write_byte(&mut rom, &mut ram, 0x4000, 0x03);
+            mapper.read_byte(&rom, &ram, 0x4000);
+            mapper.write_byte(&mut rom, &mut ram, 0xA000, 0x12);
+            assert_eq!(mapper.read_byte(&rom, &ram, 0xA000), 0xFF);
         }
     }
 }
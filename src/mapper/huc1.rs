@@ -0,0 +1,102 @@
+use super::infrared::Infrared;
+use super::MapperImpl;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// Hudson HuC1
+// MBC1 に近いが、RAM有効フラグの代わりに RAM / 赤外線 の切り替えがある
+pub struct HuC1 {
+    bank: u8,
+    ram_bank: u8,
+    ir_mode: bool,
+    rom_banks: usize,
+    pub infrared: Infrared,
+}
+
+impl HuC1 {
+    pub fn new(rom_banks: usize) -> Self {
+        HuC1 {
+            bank: 1,
+            ram_bank: 0,
+            ir_mode: false,
+            rom_banks,
+            infrared: Infrared::new(),
+        }
+    }
+
+    fn ram_addr(&self, ram: &[u8], addr: u16) -> usize {
+        let ram_banks = (ram.len() / 0x2000).max(1);
+        let bank = self.ram_bank as usize % ram_banks;
+        addr as usize - 0xA000 + (bank * 0x2000)
+    }
+}
+
+impl MapperImpl for HuC1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank);
+        w.write_u8(self.ram_bank);
+        w.write_bool(self.ir_mode);
+        self.infrared.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.bank = r.read_u8()?;
+        self.ram_bank = r.read_u8()?;
+        self.ir_mode = r.read_bool()?;
+        self.infrared.load_state(r)
+    }
+
+    fn read_byte(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            // bank0
+            0x0000..=0x3FFF => rom.get(addr as usize).copied().unwrap_or(0xFF),
+            // bank1
+            0x4000..=0x7FFF => {
+                let bank = self.bank as usize % self.rom_banks;
+                let addr = addr as usize - 0x4000 + (bank * 0x4000);
+                rom.get(addr).copied().unwrap_or(0xFF)
+            }
+            0xA000..=0xBFFF => {
+                if self.ir_mode {
+                    self.infrared.read()
+                } else {
+                    ram.get(self.ram_addr(ram, addr)).copied().unwrap_or(0xFF)
+                }
+            }
+            _ => panic!("should not reach!"),
+        }
+    }
+
+    fn write_byte(&mut self, _rom: &mut [u8], ram: &mut [u8], addr: u16, value: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF => {
+                // 0x0E: 赤外線, それ以外: RAM
+                self.ir_mode = value & 0x0F == 0x0E;
+            }
+            0x2000..=0x3FFF => {
+                // ROMバンク番号 (6bit)
+                self.bank = value & 0x3F;
+                if self.bank == 0 {
+                    self.bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => {
+                // RAMバンク番号
+                self.ram_bank = value & 0x03;
+            }
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if self.ir_mode {
+                    self.infrared.write(value);
+                } else {
+                    let addr = self.ram_addr(ram, addr);
+                    if let Some(byte) = ram.get_mut(addr) {
+                        *byte = value;
+                        return true;
+                    }
+                }
+            }
+            _ => panic!("should not reach!"),
+        }
+        false
+    }
+}
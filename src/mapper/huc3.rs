@@ -0,0 +1,341 @@
+use super::infrared::Infrared;
+use super::now;
+use super::MapperImpl;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// .save の後ろに付ける RTC のデータのサイズ
+//   時刻を保存した時の UNIX time (u64)
+//   分 (0-1439) (u16)
+//   日 (u16)
+//   RTCのメモリ (4bit * 256)
+pub const RTC_FOOTER_SIZE: usize = 8 + 2 + 2 + 0x100;
+
+// Hudson HuC3
+// 0x0000-0x1FFF に書いたモードで、0xA000-0xBFFF の意味が変わる
+//   0x0 RAM (読み込みのみ)
+//   0xA RAM (読み書き)
+//   0xB RTCへのコマンド
+//   0xC RTCからの応答
+//   0xD RTCの準備ができているか
+//   0xE 赤外線
+pub struct HuC3 {
+    bank: u8,
+    ram_bank: u8,
+    mode: u8,
+    rom_banks: usize,
+    rtc: Rtc,
+    pub infrared: Infrared,
+}
+
+impl HuC3 {
+    pub fn new(rom_banks: usize) -> Self {
+        HuC3 {
+            bank: 1,
+            ram_bank: 0,
+            mode: 0,
+            rom_banks,
+            rtc: Rtc::new(now()),
+            infrared: Infrared::new(),
+        }
+    }
+
+    fn ram_addr(&self, ram: &[u8], addr: u16) -> usize {
+        let ram_banks = (ram.len() / 0x2000).max(1);
+        let bank = self.ram_bank as usize % ram_banks;
+        addr as usize - 0xA000 + (bank * 0x2000)
+    }
+}
+
+impl MapperImpl for HuC3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank);
+        w.write_u8(self.ram_bank);
+        w.write_u8(self.mode);
+        w.write_bytes(&self.rtc.to_footer());
+        w.write_u8(self.rtc.address);
+        w.write_u8(self.rtc.command);
+        w.write_u8(self.rtc.response);
+        self.infrared.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.bank = r.read_u8()?;
+        self.ram_bank = r.read_u8()?;
+        self.mode = r.read_u8()?;
+        let mut footer = [0; RTC_FOOTER_SIZE];
+        r.read_bytes(&mut footer)?;
+        self.rtc = Rtc::from_footer(&footer).unwrap();
+        self.rtc.address = r.read_u8()?;
+        self.rtc.command = r.read_u8()?;
+        self.rtc.response = r.read_u8()?;
+        self.infrared.load_state(r)
+    }
+
+    fn read_byte(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            // bank0
+            0x0000..=0x3FFF => rom.get(addr as usize).copied().unwrap_or(0xFF),
+            // bank1
+            0x4000..=0x7FFF => {
+                let bank = self.bank as usize % self.rom_banks;
+                let addr = addr as usize - 0x4000 + (bank * 0x4000);
+                rom.get(addr).copied().unwrap_or(0xFF)
+            }
+            0xA000..=0xBFFF => match self.mode {
+                0x0 | 0xA => ram.get(self.ram_addr(ram, addr)).copied().unwrap_or(0xFF),
+                0xC => 0x80 | (self.rtc.command << 4) | self.rtc.response,
+                // いつでも準備ができている
+                0xD => 0xFF,
+                0xE => self.infrared.read(),
+                _ => 0xFF,
+            },
+            _ => panic!("should not reach!"),
+        }
+    }
+
+    fn write_byte(&mut self, _rom: &mut [u8], ram: &mut [u8], addr: u16, value: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.mode = value & 0x0F;
+            }
+            0x2000..=0x3FFF => {
+                // ROMバンク番号 (7bit)
+                self.bank = value & 0x7F;
+                if self.bank == 0 {
+                    self.bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => {
+                // RAMバンク番号
+                self.ram_bank = value & 0x03;
+            }
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF => match self.mode {
+                0xA => {
+                    let addr = self.ram_addr(ram, addr);
+                    if let Some(byte) = ram.get_mut(addr) {
+                        *byte = value;
+                        return true;
+                    }
+                }
+                0xB => return self.rtc.execute(value, now()),
+                0xE => self.infrared.write(value),
+                _ => {}
+            },
+            _ => panic!("should not reach!"),
+        }
+        false
+    }
+
+    // RAMの後ろにRTCのデータを付ける
+    fn export_battery(&mut self, ram: &[u8]) -> Vec<u8> {
+        self.rtc.update(now());
+        let mut data = ram.to_vec();
+        data.extend(self.rtc.to_footer());
+        data
+    }
+
+    // RTCのデータが付いていない .save も読み込める
+    fn import_battery(&mut self, ram: &mut [u8], data: &[u8]) -> bool {
+        if data.len() < ram.len() {
+            return false;
+        }
+        let (data, footer) = data.split_at(ram.len());
+        if !footer.is_empty() {
+            match Rtc::from_footer(footer) {
+                Some(rtc) => self.rtc = rtc,
+                None => return false,
+            }
+        }
+        ram.copy_from_slice(data);
+        true
+    }
+
+    fn tick_frame(&mut self) {
+        self.rtc.update(now());
+    }
+}
+
+// HuC3 のRTC
+// 4bit * 256 のメモリを持っていて、コマンドで読み書きする。
+//   0x00-0x02 分 (0-1439)
+//   0x03-0x05 日
+// 時計の値は、コマンド 0x60 でメモリにコピーし、0x61 でメモリから設定する。
+struct Rtc {
+    minutes: u16,
+    days: u16,
+    // minutes, days がいつの時刻のものか (UNIX time)
+    timestamp: u64,
+    memory: [u8; 0x100],
+    address: u8,
+    command: u8,
+    response: u8,
+}
+
+impl Rtc {
+    fn new(now: u64) -> Self {
+        Rtc {
+            minutes: 0,
+            days: 0,
+            timestamp: now,
+            memory: [0; 0x100],
+            address: 0,
+            command: 0,
+            response: 0,
+        }
+    }
+
+    // 前回から経過した分だけ時計を進める (1分単位)
+    fn update(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.timestamp) / 60;
+        self.timestamp += elapsed * 60;
+        let minutes = self.minutes as u64 + elapsed;
+        self.minutes = (minutes % 1440) as u16;
+        self.days = ((self.days as u64 + minutes / 1440) & 0xFFF) as u16;
+    }
+
+    // 上位4bit: コマンド, 下位4bit: 引数
+    // メモリか時刻を書き換えた場合は true
+    fn execute(&mut self, value: u8, now: u64) -> bool {
+        let command = (value >> 4) & 0x07;
+        let arg = value & 0x0F;
+        let changed = matches!((command, arg), (0x3, _) | (0x6, 0x1));
+        match command {
+            // 読み込み (アドレスを進める)
+            0x1 => {
+                self.response = self.memory[self.address as usize] & 0x0F;
+                self.address = self.address.wrapping_add(1);
+            }
+            // 書き込み (アドレスを進める)
+            0x3 => {
+                self.memory[self.address as usize] = arg;
+                self.address = self.address.wrapping_add(1);
+            }
+            // アドレスの下位4bit
+            0x4 => self.address = (self.address & 0xF0) | arg,
+            // アドレスの上位4bit
+            0x5 => self.address = (self.address & 0x0F) | (arg << 4),
+            0x6 => match arg {
+                // 現在の時刻をメモリにコピーする
+                0x0 => {
+                    self.update(now);
+                    for i in 0..3 {
+                        self.memory[i] = ((self.minutes >> (i * 4)) & 0x0F) as u8;
+                        self.memory[3 + i] = ((self.days >> (i * 4)) & 0x0F) as u8;
+                    }
+                }
+                // メモリの値を時刻に設定する
+                0x1 => {
+                    let nibbles = |start: usize| {
+                        (0..3).fold(0, |value, i| {
+                            value | (self.memory[start + i] as u16 & 0x0F) << (i * 4)
+                        })
+                    };
+                    self.minutes = nibbles(0) % 1440;
+                    self.days = nibbles(3);
+                    self.timestamp = now;
+                }
+                // 状態の確認。常に正常
+                0x2 => self.response = 0x1,
+                // 0xE: 音を鳴らす (未対応)
+                _ => {}
+            },
+            _ => {}
+        }
+        self.command = command;
+        changed
+    }
+
+    fn to_footer(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        footer.extend_from_slice(&self.timestamp.to_le_bytes());
+        footer.extend_from_slice(&self.minutes.to_le_bytes());
+        footer.extend_from_slice(&self.days.to_le_bytes());
+        footer.extend_from_slice(&self.memory);
+        footer
+    }
+
+    fn from_footer(footer: &[u8]) -> Option<Self> {
+        if footer.len() != RTC_FOOTER_SIZE {
+            return None;
+        }
+        let mut rtc = Rtc::new(u64::from_le_bytes(footer[0..8].try_into().unwrap()));
+        rtc.minutes = u16::from_le_bytes([footer[8], footer[9]]) % 1440;
+        rtc.days = u16::from_le_bytes([footer[10], footer[11]]) & 0xFFF;
+        rtc.memory.copy_from_slice(&footer[12..]);
+        Some(rtc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_nibbles(rtc: &mut Rtc, address: u8, count: usize) -> Vec<u8> {
+        rtc.execute(0x40 | (address & 0x0F), 0);
+        rtc.execute(0x50 | (address >> 4), 0);
+        (0..count)
+            .map(|_| {
+                rtc.execute(0x10, 0);
+                rtc.response
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rtc() {
+        let mut rtc = Rtc::new(0);
+        // 1日と 0x123 分
+        rtc.execute(0x60, 60 * (1440 + 0x123) + 59);
+        assert_eq!(read_nibbles(&mut rtc, 0x00, 6), vec![3, 2, 1, 1, 0, 0]);
+
+        // 時刻を設定する
+        rtc.execute(0x40, 0);
+        rtc.execute(0x50, 0);
+        for nibble in [0x0, 0x0, 0x0, 0x5, 0x0, 0x0] {
+            rtc.execute(0x30 | nibble, 0);
+        }
+        rtc.execute(0x61, 1000);
+        rtc.execute(0x60, 1000 + 60 * 1440);
+        assert_eq!(read_nibbles(&mut rtc, 0x00, 6), vec![0, 0, 0, 6, 0, 0]);
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut rom = vec![0; 0x8000];
+        let mut ram = vec![0; 0x2000];
+        let mut huc3 = HuC3::new(2);
+
+        // 読み込みのみのモードでは書き込めない
+        huc3.write_byte(&mut rom, &mut ram, 0xA000, 0x12);
+        assert_eq!(huc3.read_byte(&rom, &ram, 0xA000), 0x00);
+        huc3.write_byte(&mut rom, &mut ram, 0x0000, 0x0A);
+        huc3.write_byte(&mut rom, &mut ram, 0xA000, 0x12);
+        assert_eq!(huc3.read_byte(&rom, &ram, 0xA000), 0x12);
+
+        huc3.write_byte(&mut rom, &mut ram, 0x0000, 0x0B);
+        huc3.write_byte(&mut rom, &mut ram, 0xA000, 0x62);
+        huc3.write_byte(&mut rom, &mut ram, 0x0000, 0x0C);
+        assert_eq!(huc3.read_byte(&rom, &ram, 0xA000), 0xE1);
+
+        huc3.write_byte(&mut rom, &mut ram, 0x0000, 0x0E);
+        assert_eq!(huc3.read_byte(&rom, &ram, 0xA000), 0xC0);
+        huc3.write_byte(&mut rom, &mut ram, 0xA000, 0x01);
+        assert!(huc3.infrared.led());
+    }
+
+    #[test]
+    fn test_footer() {
+        let mut rtc = Rtc::new(100);
+        rtc.minutes = 1000;
+        rtc.days = 20;
+        rtc.memory[0x10] = 0x0F;
+        let footer = rtc.to_footer();
+        assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+        let loaded = Rtc::from_footer(&footer).unwrap();
+        assert_eq!(loaded.minutes, 1000);
+        assert_eq!(loaded.days, 20);
+        assert_eq!(loaded.timestamp, 100);
+        assert_eq!(loaded.memory[0x10], 0x0F);
+    }
+}
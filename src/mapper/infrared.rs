@@ -0,0 +1,54 @@
+// HuC1 / HuC3 の赤外線ポート
+// 今は通信相手がいないので、何も受信しない。
+// 赤外線通信をエミュレートする時に set_light で受信した光を渡す。
+
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+pub struct Infrared {
+    led: bool,
+    light: bool,
+}
+
+impl Infrared {
+    pub fn new() -> Self {
+        Infrared {
+            led: false,
+            light: false,
+        }
+    }
+
+    // bit0: 1 = 光を受信している
+    pub fn read(&self) -> u8 {
+        0xC0 | self.light as u8
+    }
+
+    // bit0: 1 = LEDを点灯する
+    pub fn write(&mut self, value: u8) {
+        self.led = value & 0x01 != 0;
+    }
+
+    pub fn led(&self) -> bool {
+        self.led
+    }
+
+    pub fn set_light(&mut self, light: bool) {
+        self.light = light;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.led);
+        w.write_bool(self.light);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.led = r.read_bool()?;
+        self.light = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Infrared {
+    fn default() -> Self {
+        Self::new()
+    }
+}
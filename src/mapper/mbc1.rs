@@ -1,112 +1,96 @@
+use super::MapperImpl;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
 pub struct MBC1 {
     bank: u8,
     ram_enabled: bool,
     secondary_bank: u8, // u2
     mode: u8,           // u1
+    rom_banks: usize,
 }
 
 impl MBC1 {
-    pub fn new() -> Self {
+    pub fn new(rom_banks: usize) -> Self {
         MBC1 {
             bank: 1,
             ram_enabled: false,
             secondary_bank: 0,
             mode: 1,
+            rom_banks,
         }
     }
 
-    pub fn read_byte(&mut self, rom: &Vec<u8>, ram: &Vec<u8>, addr: u16) -> u8 {
-        if self.mode == 0x00 {
-            self.read_byte_normal(rom, ram, addr)
-        } else {
-            self.read_byte_advanced(rom, ram, addr)
-        }
+    // ROMのサイズを超えるバンクは折り返す。
+    // ROMが512KiB以下の場合は、secondary_bank の影響を受けない
+    fn rom_addr(&self, bank: usize, addr: u16) -> usize {
+        (bank % self.rom_banks) * 0x4000 + (addr as usize & 0x3FFF)
     }
 
-    fn is_big_rom(&mut self, raw: &Vec<u8>) -> bool {
-        raw.len() >= 1024 * 1024
+    // normal モードでは、RAMはbank0固定
+    // RAMが8KiB以下の場合は、secondary_bank の影響を受けない
+    fn ram_addr(&self, ram: &[u8], addr: u16) -> usize {
+        let bank = if self.mode == 0x00 {
+            0
+        } else {
+            self.secondary_bank as usize & 0x03
+        };
+        let ram_banks = (ram.len() / 0x2000).max(1);
+        (bank % ram_banks) * 0x2000 + (addr as usize - 0xA000)
     }
+}
 
-    fn is_big_ram(&mut self, raw: &Vec<u8>) -> bool {
-        raw.len() >= 8 * 1024
+impl MapperImpl for MBC1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank);
+        w.write_bool(self.ram_enabled);
+        w.write_u8(self.secondary_bank);
+        w.write_u8(self.mode);
     }
 
-    fn bank1_addr(&mut self, addr: u16) -> usize {
-        let mut bank = self.bank & 0x1F;
-        if bank == 0 {
-            bank = 1
-        }
-        let addr = addr as usize + (bank as usize * 0x4000);
-        addr - 0x4000
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.bank = r.read_u8()?;
+        self.ram_enabled = r.read_bool()?;
+        self.secondary_bank = r.read_u8()?;
+        self.mode = r.read_u8()?;
+        Ok(())
     }
 
-    fn bank1_addr_with_secondary_bank(&mut self, addr: u16) -> usize {
-        let mut bank = ((self.secondary_bank & 0x03) << 5) | (self.bank & 0x1F);
-        if bank == 0 {
-            bank = 1
-        }
-        let addr = addr as usize + (bank as usize * 0x4000);
-        addr as usize - 0x4000
-    }
-
-    pub fn read_byte_normal(&mut self, rom: &Vec<u8>, ram: &Vec<u8>, addr: u16) -> u8 {
-        match addr {
-            // bank0
-            0x0000..=0x3FFF => rom[addr as usize],
-            // bank1
-            0x4000..=0x7FFF => {
-                if !self.is_big_rom(rom) && self.is_big_ram(ram) {
-                    // カートリッジのROMが1MiB未満で、RAMが8KiBより大きい場合、
-                    // secondary_bankの影響を受けない
-                    rom[self.bank1_addr(addr)]
-                } else {
-                    rom[self.bank1_addr_with_secondary_bank(addr)]
-                }
-            }
-            0xA000..=0xBFFF => ram[addr as usize - 0xA000],
-            _ => panic!("should not reach!"),
-        }
-    }
-
-    pub fn read_byte_advanced(&mut self, rom: &Vec<u8>, ram: &Vec<u8>, addr: u16) -> u8 {
+    fn read_byte(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
         match addr {
             // bank0
             0x0000..=0x3FFF => {
-                if self.is_big_rom(rom) {
-                    let bank = self.secondary_bank & 0x03 << 5;
-                    let addr = addr as usize + (bank as usize * 0x4000);
-                    rom[addr as usize]
+                // advanced モードでは、大容量ROMカートリッジのbank0も切り替わる
+                let bank = if self.mode == 0x00 {
+                    0
                 } else {
-                    rom[addr as usize]
-                }
+                    (self.secondary_bank as usize & 0x03) << 5
+                };
+                rom.get(self.rom_addr(bank, addr)).copied().unwrap_or(0xFF)
             }
             // bank1
             0x4000..=0x7FFF => {
-                if self.is_big_rom(rom) {
-                    rom[self.bank1_addr_with_secondary_bank(addr)]
-                } else {
-                    rom[self.bank1_addr(addr)]
+                let mut bank = self.bank as usize & 0x1F;
+                if bank == 0 {
+                    bank = 1
                 }
+                let bank = ((self.secondary_bank as usize & 0x03) << 5) | bank;
+                rom.get(self.rom_addr(bank, addr)).copied().unwrap_or(0xFF)
             }
             0xA000..=0xBFFF => {
-                if self.is_big_ram(ram) {
-                    // 大容量RAMカートリッジ
-                    let bank = self.secondary_bank & 0x03;
-                    let addr = addr as usize + (bank as usize * 0x2000);
-                    ram[addr as usize - 0xA000]
-                } else {
-                    ram[addr as usize - 0xA000]
+                if !self.ram_enabled {
+                    return 0xFF;
                 }
+                ram.get(self.ram_addr(ram, addr)).copied().unwrap_or(0xFF)
             }
             _ => panic!("should not reach!"),
         }
     }
 
-    pub fn write_byte(&mut self, rom: &mut Vec<u8>, ram: &mut Vec<u8>, addr: u16, value: u8) {
+    fn write_byte(&mut self, rom: &mut [u8], ram: &mut [u8], addr: u16, value: u8) -> bool {
         match addr {
             0x0000..=0x1FFF => {
                 // RAM有効フラグ (W)
-                self.ram_enabled = value == 0x0A;
+                self.ram_enabled = value & 0x0F == 0x0A;
             }
             0x2000..=0x3FFF => {
                 self.bank = value;
@@ -117,28 +101,20 @@ impl MBC1 {
             }
             0x6000..=0x7FFF => {
                 // バンクモードセレクト (W)
-                self.mode = value;
+                self.mode = value & 0x01;
             }
             0xA000..=0xBFFF => {
                 if !self.ram_enabled {
-                    return;
+                    return false;
                 }
-                if self.mode == 0x00 {
-                    // normal
-                    ram[addr as usize - 0xA000] = value;
-                } else {
-                    // advanced
-                    if self.is_big_ram(ram) {
-                        // 大容量RAMカートリッジ
-                        let bank = self.secondary_bank & 0x03;
-                        let addr = addr as usize + (bank as usize * 0x2000);
-                        ram[addr as usize - 0xA000] = value;
-                    } else {
-                        ram[addr as usize - 0xA000] = value;
-                    }
+                let addr = self.ram_addr(ram, addr);
+                if let Some(byte) = ram.get_mut(addr) {
+                    *byte = value;
+                    return true;
                 }
             }
             _ => panic!("should not reach!"),
         }
+        false
     }
 }
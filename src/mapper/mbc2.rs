@@ -0,0 +1,140 @@
+use super::MapperImpl;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// MBC2 はカートリッジの中に 512 x 4bit のRAMを持っている
+pub const RAM_SIZE: usize = 512;
+
+pub struct MBC2 {
+    bank: u8,
+    ram_enabled: bool,
+    rom_banks: usize,
+}
+
+impl MBC2 {
+    pub fn new(rom_banks: usize) -> Self {
+        MBC2 {
+            bank: 1,
+            ram_enabled: false,
+            rom_banks,
+        }
+    }
+
+    // 0xA000-0xA1FF が 0xBFFF まで繰り返し見える
+    fn ram_addr(addr: u16) -> usize {
+        (addr as usize - 0xA000) % RAM_SIZE
+    }
+}
+
+impl MapperImpl for MBC2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank);
+        w.write_bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.bank = r.read_u8()?;
+        self.ram_enabled = r.read_bool()?;
+        Ok(())
+    }
+
+    fn read_byte(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            // bank0
+            0x0000..=0x3FFF => rom.get(addr as usize).copied().unwrap_or(0xFF),
+            // bank1
+            0x4000..=0x7FFF => {
+                let bank = self.bank as usize % self.rom_banks;
+                let addr = addr as usize - 0x4000 + (bank * 0x4000);
+                rom.get(addr).copied().unwrap_or(0xFF)
+            }
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                // 下位4bitだけ。上位4bitは1が読める
+                match ram.get(Self::ram_addr(addr)) {
+                    Some(value) => value | 0xF0,
+                    None => 0xFF,
+                }
+            }
+            _ => panic!("should not reach!"),
+        }
+    }
+
+    fn write_byte(&mut self, _rom: &mut [u8], ram: &mut [u8], addr: u16, value: u8) -> bool {
+        match addr {
+            0x0000..=0x3FFF => {
+                if addr & 0x0100 == 0 {
+                    // アドレスのbit8が0: RAM有効フラグ
+                    self.ram_enabled = value & 0x0F == 0x0A;
+                } else {
+                    // アドレスのbit8が1: ROMバンク番号 (4bit)
+                    self.bank = value & 0x0F;
+                    if self.bank == 0 {
+                        self.bank = 1;
+                    }
+                }
+            }
+            0x4000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return false;
+                }
+                if let Some(byte) = ram.get_mut(Self::ram_addr(addr)) {
+                    *byte = value & 0x0F;
+                    return true;
+                }
+            }
+            _ => panic!("should not reach!"),
+        }
+        false
+    }
+
+    fn internal_ram_size(&self) -> Option<usize> {
+        Some(RAM_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_registers() {
+        let mut rom = vec![0; 0x4000 * 16];
+        for bank in 0..16 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        let mut ram = vec![0; RAM_SIZE];
+        let mut mbc2 = MBC2::new(16);
+
+        // bit8 が1ならROMバンク
+        mbc2.write_byte(&mut rom, &mut ram, 0x2100, 0x05);
+        assert_eq!(mbc2.read_byte(&rom, &ram, 0x4000), 0x05);
+        mbc2.write_byte(&mut rom, &mut ram, 0x0100, 0x00);
+        assert_eq!(mbc2.read_byte(&rom, &ram, 0x4000), 0x01);
+        assert!(!mbc2.ram_enabled);
+
+        // bit8 が0ならRAM有効フラグ
+        mbc2.write_byte(&mut rom, &mut ram, 0x3000, 0x0A);
+        assert!(mbc2.ram_enabled);
+        assert_eq!(mbc2.read_byte(&rom, &ram, 0x4000), 0x01);
+    }
+
+    #[test]
+    fn test_ram() {
+        let mut rom = vec![0; 0x8000];
+        let mut ram = vec![0; RAM_SIZE];
+        let mut mbc2 = MBC2::new(2);
+
+        mbc2.write_byte(&mut rom, &mut ram, 0xA000, 0x12);
+        assert_eq!(mbc2.read_byte(&rom, &ram, 0xA000), 0xFF);
+
+        mbc2.write_byte(&mut rom, &mut ram, 0x0000, 0x0A);
+        mbc2.write_byte(&mut rom, &mut ram, 0xA000, 0x12);
+        assert_eq!(ram[0], 0x02);
+        assert_eq!(mbc2.read_byte(&rom, &ram, 0xA000), 0xF2);
+        assert_eq!(mbc2.read_byte(&rom, &ram, 0xA200), 0xF2);
+        assert_eq!(mbc2.read_byte(&rom, &ram, 0xBE00), 0xF2);
+    }
+}
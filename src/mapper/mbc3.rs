@@ -1,99 +1,387 @@
-use chrono::{DateTime, Timelike, Utc};
+use super::now;
+use super::MapperImpl;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+// .save の後ろに付ける RTC のデータのサイズ (VBA-M / BGB と同じ形式)
+//   現在のレジスタ 秒, 分, 時, 日(下位), 日(上位) (u32 * 5)
+//   ラッチしたレジスタ 秒, 分, 時, 日(下位), 日(上位) (u32 * 5)
+//   保存した時刻 UNIX time (u64)
+pub const RTC_FOOTER_SIZE: usize = 48;
+// タイムスタンプが32bitの古い形式
+const RTC_FOOTER_SIZE_OLD: usize = 44;
 
 pub struct MBC3 {
     bank: u8,
     ram_enabled: bool,
     ram_bank: u8,
     latch_clock_data: u8,
-    rtc: [u8; 5],
+    rtc: Option<Rtc>,
+    rom_banks: usize,
 }
 
 impl MBC3 {
-    pub fn new() -> Self {
+    pub fn new(rom_banks: usize, has_rtc: bool) -> Self {
         MBC3 {
             bank: 1,
             ram_enabled: false,
             ram_bank: 0,
             latch_clock_data: 0,
-            rtc: [0; 5],
+            rtc: if has_rtc { Some(Rtc::new(now())) } else { None },
+            rom_banks,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) -> bool {
+        if let Some(addr) = self.ram_addr(ram, addr) {
+            ram[addr] = value;
+            return true;
+        }
+        false
+    }
+
+    // RAMバンクは0x00-0x03 (MBC30は0x00-0x07)。
+    // RAMのサイズを超えるバンクはつながっていない
+    fn ram_addr(&self, ram: &[u8], addr: u16) -> Option<usize> {
+        let addr = addr as usize - 0xA000 + self.ram_bank as usize * 0x2000;
+        if addr < ram.len() {
+            Some(addr)
+        } else {
+            None
+        }
+    }
+}
+
+impl MapperImpl for MBC3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank);
+        w.write_bool(self.ram_enabled);
+        w.write_u8(self.ram_bank);
+        w.write_u8(self.latch_clock_data);
+        if let Some(rtc) = &self.rtc {
+            w.write_bytes(&rtc.to_footer());
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.bank = r.read_u8()?;
+        self.ram_enabled = r.read_bool()?;
+        self.ram_bank = r.read_u8()?;
+        self.latch_clock_data = r.read_u8()?;
+        if let Some(rtc) = &mut self.rtc {
+            let mut footer = [0; RTC_FOOTER_SIZE];
+            r.read_bytes(&mut footer)?;
+            *rtc = Rtc::from_footer(&footer).unwrap();
         }
+        Ok(())
     }
 
-    pub fn read_byte(&mut self, rom: &Vec<u8>, ram: &Vec<u8>, addr: u16) -> u8 {
+    fn read_byte(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
         match addr {
             // bank0
-            0x0000..=0x3FFF => rom[addr as usize],
+            0x0000..=0x3FFF => rom.get(addr as usize).copied().unwrap_or(0xFF),
             // bank1
             0x4000..=0x7FFF => {
-                // MEMO: 1MBのソフトのみ対応。2MBの場合は、0x7Fでマスクする必要あり。
-                let bank = if (self.bank & 0x3F) == 0 {
-                    1
-                } else {
-                    self.bank & 0x3F
-                };
-                let addr = addr as usize - 0x4000 + (bank as usize * 0x4000);
-                rom[addr]
+                let bank = if self.bank == 0 { 1 } else { self.bank };
+                let bank = bank as usize % self.rom_banks;
+                let addr = addr as usize - 0x4000 + (bank * 0x4000);
+                rom.get(addr).copied().unwrap_or(0xFF)
             }
             0xA000..=0xBFFF => {
                 if !self.ram_enabled {
-                    0
-                } else if self.ram_bank <= 0x03 {
-                    ram[addr as usize - 0xA000 + self.ram_bank as usize * 0x2000]
-                } else if self.ram_bank >= 0x08 {
-                    self.rtc[self.ram_bank as usize - 0x08]
+                    0xFF
+                } else if self.ram_bank <= 0x07 {
+                    match self.ram_addr(ram, addr) {
+                        Some(addr) => ram[addr],
+                        None => 0xFF,
+                    }
+                } else if let Some(rtc) = &self.rtc {
+                    rtc.read(self.ram_bank)
                 } else {
-                    0
+                    0xFF
                 }
             }
             _ => panic!("should not reach!"),
         }
     }
 
-    pub fn write_byte(&mut self, rom: &mut Vec<u8>, ram: &mut Vec<u8>, addr: u16, value: u8) {
+    fn write_byte(&mut self, rom: &mut [u8], ram: &mut [u8], addr: u16, value: u8) -> bool {
         match addr {
             0x0000..=0x1FFF => {
                 // RAM/タイマー有効化フラグ
-                self.ram_enabled = value == 0x0A;
+                self.ram_enabled = value & 0x0F == 0x0A;
             }
             0x2000..=0x3FFF => {
-                // ROMバンク番号
-                self.bank = value & 0x3F;
-                // println!("BANK: {:02X}, {}", value, value);
+                // ROMバンク番号 (7bit, MBC30は8bit)
+                self.bank = if self.rom_banks > 0x80 {
+                    value
+                } else {
+                    value & 0x7F
+                };
             }
             0x4000..=0x5FFF => {
                 // RAMバンク/RTCレジスタの選択レジスタ
                 self.ram_bank = value & 0x0F;
             }
             0x6000..=0x7FFF => {
-                // ラッチクロックデータ
+                // ラッチクロックデータ (0x00 -> 0x01 で現在の時刻をラッチする)
                 if self.latch_clock_data == 0x00 && value == 0x01 {
-                    let now: DateTime<Utc> = Utc::now();
-                    self.rtc[0] = now.second() as u8;
-                    self.rtc[1] = now.minute() as u8;
-                    self.rtc[2] = now.hour() as u8;
-                    self.rtc[3] = 0; // FIXME 日付が入るが、省略！
-                    self.rtc[4] = 0;
+                    if let Some(rtc) = &mut self.rtc {
+                        rtc.update(now());
+                        rtc.latch();
+                    }
                 }
                 self.latch_clock_data = value;
             }
             0xA000..=0xBFFF => {
                 // RAM書き込み / RTCレジスタ書き込み
                 if !self.ram_enabled {
-                } else if self.ram_bank <= 0x03 {
-                    self.write_ram(ram, addr, value)
-                } else if self.ram_bank >= 0x08 {
-                    self.write_rtc(value)
+                } else if self.ram_bank <= 0x07 {
+                    return self.write_ram(ram, addr, value);
+                } else if let Some(rtc) = &mut self.rtc {
+                    rtc.update(now());
+                    rtc.write(self.ram_bank, value);
+                    return true;
                 }
             }
             _ => panic!("should not reach!"),
         }
+        false
+    }
+
+    // RTCを持っている場合は、RAMの後ろに付ける
+    fn export_battery(&mut self, ram: &[u8]) -> Vec<u8> {
+        let mut data = ram.to_vec();
+        if let Some(rtc) = &mut self.rtc {
+            rtc.update(now());
+            data.extend(rtc.to_footer());
+        }
+        data
+    }
+
+    // RTCのデータが付いていない .save も読み込める
+    fn import_battery(&mut self, ram: &mut [u8], data: &[u8]) -> bool {
+        if data.len() < ram.len() {
+            return false;
+        }
+        let (data, footer) = data.split_at(ram.len());
+        if !footer.is_empty() {
+            match (&mut self.rtc, Rtc::from_footer(footer)) {
+                (Some(rtc), Some(loaded)) => *rtc = loaded,
+                _ => return false,
+            }
+        }
+        ram.copy_from_slice(data);
+        true
+    }
+
+    fn tick_frame(&mut self) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.update(now());
+        }
+    }
+}
+
+// MBC3 のリアルタイムクロック
+//   08 秒 (0-59)
+//   09 分 (0-59)
+//   0A 時 (0-23)
+//   0B 日 (下位8bit)
+//   0C bit0: 日 (bit8), bit6: 停止, bit7: 日のキャリー
+pub struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16, // u9
+    halt: bool,
+    carry: bool,
+    latched: [u8; 5],
+    // レジスタの値がいつの時刻のものか (UNIX time)
+    timestamp: u64,
+}
+
+impl Rtc {
+    pub fn new(now: u64) -> Self {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            timestamp: now,
+        }
+    }
+
+    // 前回から経過した分だけ時計を進める
+    pub fn update(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.timestamp);
+        self.timestamp = now;
+        if self.halt || elapsed == 0 {
+            return;
+        }
+
+        let seconds = self.seconds as u64 + elapsed;
+        self.seconds = (seconds % 60) as u8;
+        let minutes = self.minutes as u64 + seconds / 60;
+        self.minutes = (minutes % 60) as u8;
+        let hours = self.hours as u64 + minutes / 60;
+        self.hours = (hours % 24) as u8;
+        let days = self.days as u64 + hours / 24;
+        if days >= 512 {
+            self.carry = true;
+        }
+        self.days = (days % 512) as u16;
+    }
+
+    pub fn latch(&mut self) {
+        self.latched = self.registers();
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08..=0x0C => self.latched[register as usize - 0x08],
+            _ => 0xFF,
+        }
+    }
+
+    // update を呼んでから書き込むこと
+    pub fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 0x01) << 8);
+                self.halt = value & 0x40 != 0;
+                self.carry = value & 0x80 != 0;
+            }
+            _ => return,
+        }
+        // 書き込んだ値はすぐに読めるようにする
+        self.latched[register as usize - 0x08] = self.registers()[register as usize - 0x08];
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            ((self.days >> 8) as u8 & 0x01)
+                | if self.halt { 0x40 } else { 0 }
+                | if self.carry { 0x80 } else { 0 },
+        ]
+    }
+
+    pub fn to_footer(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for value in self.registers().iter().chain(self.latched.iter()) {
+            footer.extend_from_slice(&(*value as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&self.timestamp.to_le_bytes());
+        footer
     }
 
-    fn write_ram(&mut self, ram: &mut Vec<u8>, addr: u16, value: u8) {
-        ram[addr as usize - 0xA000 + self.ram_bank as usize * 0x2000] = value;
+    pub fn from_footer(footer: &[u8]) -> Option<Self> {
+        if footer.len() != RTC_FOOTER_SIZE && footer.len() != RTC_FOOTER_SIZE_OLD {
+            return None;
+        }
+        let value = |i: usize| footer[i * 4];
+        let mut rtc = Rtc::new(0);
+        rtc.seconds = value(0) & 0x3F;
+        rtc.minutes = value(1) & 0x3F;
+        rtc.hours = value(2) & 0x1F;
+        rtc.days = value(3) as u16 | ((value(4) as u16 & 0x01) << 8);
+        rtc.halt = value(4) & 0x40 != 0;
+        rtc.carry = value(4) & 0x80 != 0;
+        for i in 0..5 {
+            rtc.latched[i] = value(5 + i);
+        }
+        rtc.timestamp = if footer.len() == RTC_FOOTER_SIZE {
+            u64::from_le_bytes(footer[40..48].try_into().unwrap())
+        } else {
+            u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64
+        };
+        Some(rtc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn latched(rtc: &mut Rtc) -> [u8; 5] {
+        rtc.latch();
+        [
+            rtc.read(0x08),
+            rtc.read(0x09),
+            rtc.read(0x0A),
+            rtc.read(0x0B),
+            rtc.read(0x0C),
+        ]
+    }
+
+    #[test]
+    fn test_rtc_tick() {
+        let mut rtc = Rtc::new(1000);
+        rtc.update(1000 + 59);
+        assert_eq!(latched(&mut rtc), [59, 0, 0, 0, 0]);
+        rtc.update(1000 + 60 + 60 * 60 + 24 * 60 * 60 * 300 + 1);
+        assert_eq!(latched(&mut rtc), [1, 1, 1, 0x2C, 0x01]);
+
+        // ラッチしない限り、読める値は変わらない
+        rtc.update(1000 + 24 * 60 * 60 * 400);
+        assert_eq!(rtc.read(0x0B), 0x2C);
     }
 
-    fn write_rtc(&mut self, value: u8) {
-        self.rtc[self.ram_bank as usize - 0x08] = value;
+    #[test]
+    fn test_rtc_carry() {
+        let mut rtc = Rtc::new(0);
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+        rtc.update(24 * 60 * 60);
+        assert_eq!(latched(&mut rtc), [0, 0, 0, 0, 0x80]);
+
+        // キャリーは書き込むまで消えない
+        rtc.update(24 * 60 * 60 * 2);
+        assert_eq!(latched(&mut rtc), [0, 0, 0, 1, 0x80]);
+        rtc.write(0x0C, 0x00);
+        assert_eq!(latched(&mut rtc), [0, 0, 0, 1, 0x00]);
+    }
+
+    #[test]
+    fn test_rtc_halt() {
+        let mut rtc = Rtc::new(0);
+        rtc.write(0x0C, 0x40);
+        rtc.update(100);
+        assert_eq!(latched(&mut rtc), [0, 0, 0, 0, 0x40]);
+
+        // 書き込んだ値から再開する
+        rtc.write(0x08, 30);
+        rtc.write(0x0C, 0x00);
+        rtc.update(110);
+        assert_eq!(latched(&mut rtc), [40, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_rtc_footer() {
+        let mut rtc = Rtc::new(1_700_000_000);
+        rtc.write(0x0A, 12);
+        rtc.latch();
+        rtc.write(0x09, 34);
+        let footer = rtc.to_footer();
+        assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+
+        let mut loaded = Rtc::from_footer(&footer).unwrap();
+        assert_eq!(loaded.registers(), rtc.registers());
+        assert_eq!(loaded.latched, rtc.latched);
+
+        // 保存してから経過した時間だけ進む
+        loaded.update(1_700_000_000 + 60 * 60);
+        assert_eq!(latched(&mut loaded), [0, 34, 13, 0, 0]);
+
+        assert!(Rtc::from_footer(&footer[..40]).is_none());
     }
 }
@@ -1,44 +1,93 @@
+use super::MapperImpl;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
 pub struct MBC5 {
     bank: u16,
     ram_enabled: bool,
     ram_bank: u8,
+    rom_banks: usize,
+    has_rumble: bool,
+    rumble: bool,
+    // 前回 take_rumble を呼んでから、モーターが回ったか
+    rumble_active: bool,
 }
 
 impl MBC5 {
-    pub fn new() -> Self {
+    pub fn new(rom_banks: usize, has_rumble: bool) -> Self {
         MBC5 {
             bank: 1,
             ram_enabled: false,
             ram_bank: 0,
+            rom_banks,
+            has_rumble,
+            rumble: false,
+            rumble_active: false,
         }
     }
 
-    pub fn read_byte(&mut self, rom: &Vec<u8>, ram: &Vec<u8>, addr: u16) -> u8 {
+    // ROMのサイズを超えるバンクは折り返す
+    fn bank1_addr(&mut self, addr: u16) -> usize {
+        let bank = (self.bank & 0x1FF) as usize % self.rom_banks;
+        let addr = addr as usize + (bank * 0x4000);
+        addr - 0x4000
+    }
+
+    fn ram_addr(&self, ram: &[u8], addr: u16) -> usize {
+        let ram_banks = (ram.len() / 0x2000).max(1);
+        let bank = (self.ram_bank & 0x0F) as usize % ram_banks;
+        addr as usize - 0xA000 + (bank * 0x2000)
+    }
+}
+
+impl MapperImpl for MBC5 {
+    // 振動カートリッジの場合、前回呼んでからモーターが回ったかを返す
+    fn take_rumble(&mut self) -> Option<bool> {
+        if !self.has_rumble {
+            return None;
+        }
+        let active = self.rumble_active;
+        self.rumble_active = self.rumble;
+        Some(active)
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.bank);
+        w.write_bool(self.ram_enabled);
+        w.write_u8(self.ram_bank);
+        w.write_bool(self.rumble);
+        w.write_bool(self.rumble_active);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.bank = r.read_u16()?;
+        self.ram_enabled = r.read_bool()?;
+        self.ram_bank = r.read_u8()?;
+        self.rumble = r.read_bool()?;
+        self.rumble_active = r.read_bool()?;
+        Ok(())
+    }
+
+    fn read_byte(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
         match addr {
             // bank0
-            0x0000..=0x3FFF => rom[addr as usize],
+            0x0000..=0x3FFF => rom.get(addr as usize).copied().unwrap_or(0xFF),
             // bank1
-            0x4000..=0x7FFF => rom[self.bank1_addr(addr)],
+            0x4000..=0x7FFF => rom.get(self.bank1_addr(addr)).copied().unwrap_or(0xFF),
             0xA000..=0xBFFF => {
-                let bank = self.ram_bank & 0x0F;
-                let addr = addr as usize + (bank as usize * 0x2000);
-                ram[addr as usize - 0xA000]
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                ram.get(self.ram_addr(ram, addr)).copied().unwrap_or(0xFF)
             }
             _ => panic!("should not reach!"),
         }
     }
 
-    fn bank1_addr(&mut self, addr: u16) -> usize {
-        let mut bank = self.bank & 0x1FF;
-        let addr = addr as usize + (bank as usize * 0x4000);
-        addr - 0x4000
-    }
-
-    pub fn write_byte(&mut self, rom: &mut Vec<u8>, ram: &mut Vec<u8>, addr: u16, value: u8) {
+    fn write_byte(&mut self, rom: &mut [u8], ram: &mut [u8], addr: u16, value: u8) -> bool {
         match addr {
             0x0000..=0x1FFF => {
                 // RAM有効フラグ (W)
-                self.ram_enabled = value == 0x0A;
+                self.ram_enabled = value & 0x0F == 0x0A;
             }
             0x2000..=0x2FFF => {
                 // ROMバンク番号の下位8bit
@@ -49,16 +98,30 @@ impl MBC5 {
                 self.bank = (self.bank & 0x00FF) | ((value as u16) << 8);
             }
             0x4000..=0x5FFF => {
-                // RAMバンク番号
-                self.ram_bank = value;
+                if self.has_rumble {
+                    // 振動カートリッジでは、bit3はモーター
+                    self.rumble = value & 0x08 != 0;
+                    self.rumble_active |= self.rumble;
+                    self.ram_bank = value & 0x07;
+                } else {
+                    // RAMバンク番号
+                    self.ram_bank = value;
+                }
             }
+            0x6000..=0x7FFF => {}
             0xA000..=0xBFFF => {
                 // RAMへの書き込み
-                let bank = self.ram_bank & 0x0F;
-                let addr = addr as usize + (bank as usize * 0x2000);
-                ram[addr as usize - 0xA000] = value;
+                if !self.ram_enabled {
+                    return false;
+                }
+                let addr = self.ram_addr(ram, addr);
+                if let Some(byte) = ram.get_mut(addr) {
+                    *byte = value;
+                    return true;
+                }
             }
             _ => panic!("should not reach! addr: {:04X}", addr),
         }
+        false
     }
 }
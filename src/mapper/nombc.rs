@@ -1,15 +1,30 @@
+use super::MapperImpl;
+
 pub struct NoMBC {}
 
 impl NoMBC {
     pub fn new() -> Self {
         NoMBC {}
     }
+}
 
-    pub fn read_byte(&mut self, rom: &Vec<u8>, ram: &Vec<u8>, addr: u16) -> u8 {
-        rom[addr as usize]
+impl MapperImpl for NoMBC {
+    fn read_byte(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => rom.get(addr as usize).copied().unwrap_or(0xFF),
+            0xA000..=0xBFFF => ram.get(addr as usize - 0xA000).copied().unwrap_or(0xFF),
+            _ => panic!("should not reach!"),
+        }
     }
 
-    pub fn write_byte(&mut self, rom: &mut Vec<u8>, ram: &mut Vec<u8>, addr: u16, value: u8) {
-        panic!("should not reach!")
+    fn write_byte(&mut self, rom: &mut [u8], ram: &mut [u8], addr: u16, value: u8) -> bool {
+        // ROMへの書き込みは無視する
+        if let 0xA000..=0xBFFF = addr {
+            if let Some(byte) = ram.get_mut(addr as usize - 0xA000) {
+                *byte = value;
+                return true;
+            }
+        }
+        false
     }
 }
@@ -1,37 +1,127 @@
-use sdl2::audio::AudioQueue;
-
 use crate::{
     apu::APU,
     cartridge::Cartridge,
-    joypad::Joypad,
-    ppu::{LcdControlRegisters, LcdStatusRegisters, PPU, VRAM_BEGIN, VRAM_END},
+    cheat::GameShark,
+    joypad::{Buttons, Joypad},
+    ppu::{LcdControlRegisters, LcdStatusRegisters, PPUInterrupt, PPU, VRAM_BEGIN, VRAM_END},
+    savestate::{SaveStateError, StateReader, StateWriter},
+    timer::Timer,
 };
 
+// OAM DMA。1 M-cycle ごとに 1 バイト転送する
+#[derive(Clone, Copy)]
+struct OamDma {
+    source: u16,
+    index: u16,
+    // 転送を始めるまでの M-cycle
+    delay: u8,
+    // 転送中は CPU から OAM が読めない
+    blocking: bool,
+}
+
 pub struct MemoryBus {
     pub memory: [u8; 0x10000],
     pub cartridge: Cartridge,
     pub ppu: PPU,
     pub joypad: Joypad,
     pub apu: APU,
+    pub timer: Timer,
+    // シリアルポートに送信されたデータ
+    pub serial: Vec<u8>,
 
     // CGB
     svbk: u8,
     wram: [u8; 0x8000], // 32KB
+    pub high_speed_mode: bool,
+
+    oam_dma: Option<OamDma>,
+    // 起動してからのサイクル数
+    cycles: u64,
 }
 
 impl MemoryBus {
-    pub fn new(cartridge: Cartridge, device: AudioQueue<f32>) -> Self {
+    pub fn new(cartridge: Cartridge, sample_rate: i32) -> Self {
         MemoryBus {
             memory: [0; 0x10000],
             ppu: PPU::new(cartridge.palette),
             cartridge,
             joypad: Joypad::new(),
-            apu: APU::new(device),
+            apu: APU::new(sample_rate),
+            timer: Timer::new(),
+            serial: Vec::new(),
             svbk: 0,
             wram: [0; 0x8000],
+            high_speed_mode: false,
+            oam_dma: None,
+            cycles: 0,
         }
     }
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.memory);
+        w.write_u8(self.svbk);
+        w.write_bytes(&self.wram);
+        w.write_bool(self.high_speed_mode);
+        match self.oam_dma {
+            Some(dma) => {
+                w.write_bool(true);
+                w.write_u16(dma.source);
+                w.write_u16(dma.index);
+                w.write_u8(dma.delay);
+                w.write_bool(dma.blocking);
+            }
+            None => w.write_bool(false),
+        }
+        w.write_u64(self.cycles);
+        self.timer.save_state(w);
+        self.cartridge.save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        self.joypad.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.read_bytes(&mut self.memory)?;
+        self.svbk = r.read_u8()?;
+        r.read_bytes(&mut self.wram)?;
+        self.high_speed_mode = r.read_bool()?;
+        self.oam_dma = if r.read_bool()? {
+            Some(OamDma {
+                source: r.read_u16()?,
+                index: r.read_u16()?,
+                delay: r.read_u8()?,
+                blocking: r.read_bool()?,
+            })
+        } else {
+            None
+        };
+        self.cycles = r.read_u64()?;
+        self.timer.load_state(r)?;
+        self.cartridge.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.joypad.load_state(r)
+    }
+
+    // CPU からの読み込み。1 M-cycle 進めてから読む
     pub fn read_byte(&mut self, address: u16) -> u8 {
+        self.tick();
+        if self.oam_blocked(address) {
+            return 0xFF;
+        }
+        self.peek_byte(address)
+    }
+
+    // CPU からの書き込み。1 M-cycle 進めてから書く
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        self.tick();
+        if self.oam_blocked(address) {
+            return;
+        }
+        self.poke_byte(address, value)
+    }
+
+    // CPU 以外 (DMA, デバッガー, チートなど) からの読み込み。時間は進まない
+    pub fn peek_byte(&mut self, address: u16) -> u8 {
         let address = address as usize;
         match address {
             0x0000..=0x7FFF => self.cartridge.read_byte(address as u16),
@@ -50,11 +140,8 @@ impl MemoryBus {
             0xFF4A => self.ppu.wy,
             0xFF4B => self.ppu.wx,
             0xFE00..=0xFE9F => self.ppu.read_oam(address),
-            0xFF50 => {
-                println!("READ 0xFF50: {:02X}", self.memory[address]);
-                self.memory[address]
-            }
             0xFF00 => self.joypad.read(),
+            0xFF04..=0xFF07 => self.timer.read(address as u16),
 
             // HDMA
             0xFF51 => self.ppu.hdma1,
@@ -95,27 +182,11 @@ impl MemoryBus {
             }
             // CH4
             0xFF20 | 0xFF21 | 0xFF22 | 0xFF23 => self.apu.ch4.read(address as u16),
-            // key0, key1 (0xFF4C, 0xFF4D)
-            0xFF4C | 0xFF4D => {
-                println!("READ {:04X} {:02X}", address, self.memory[address]);
-                self.memory[address]
-            }
-            0xFF56 => {
-                println!("READ 0xFF56 0x{:02X}", self.memory[address]);
-                self.memory[address]
-            }
-            0xFF01 => {
-                println!("READ 0xFF01 (DATA) 0x{:02X}", self.memory[address]);
-                self.memory[address]
-            }
-            0xFF02 => {
-                println!("READ 0xFF02 (CTRL) 0x{:02X}", self.memory[address]);
-                self.memory[address]
-            }
             _ => self.memory[address],
         }
     }
-    pub fn write_byte(&mut self, address: u16, value: u8) {
+    // CPU 以外からの書き込み。時間は進まない
+    pub fn poke_byte(&mut self, address: u16, value: u8) {
         let address = address as usize;
         match address {
             0x0000..=0x7FFF => self.cartridge.write_byte(address as u16, value),
@@ -154,30 +225,27 @@ impl MemoryBus {
             0xFF6A => self.ppu.ocps = value,
             0xFF6B => self.ppu.write_sprite_palette(value),
             // OPRI
-            0xFF6C => {
-                println!("READ OPRI: {}", self.ppu.opri);
-                self.ppu.opri = (value & 0x01) != 0;
-            }
+            0xFF6C => self.ppu.opri = (value & 0x01) != 0,
 
             0xFF50 => self.memory[address] = value, // FIXME boot rom bank switch
             0xFF01 => {
                 // 本当はシリアル通信.
                 // テストROMがここに出力をするので、hook
-                // let res = [value, 0x00].iter().map(|&s| s as char).collect::<String>();
-                // print!("{}", res);
-                // println!("WRITE 0xFF01 (DATA) 0x{:02X}", self.memory[address]);
+                self.memory[address] = value;
             }
             0xFF02 => {
                 // BEAT MANIA2の起動時にTransfer enableを立てていて、
                 // 落ちるのを待つので、書き込まれないようにする。
-                // println!("SC: {:02X}", value);
-                // println!("WRITE 0xFF02 (CTRL) 0x{:02X}", self.memory[address]);
+                // 内部クロックで転送開始した時に、SBの値を送信したことにする。
+                if value & 0x81 == 0x81 {
+                    self.serial.push(self.memory[0xFF01]);
+                }
             }
-            0xFF04 => {
-                // DIV
-                self.memory[address] = 0;
+            0xFF04..=0xFF07 => self.timer.write(address as u16, value),
+            0xFF00 => {
+                self.joypad.write(value);
+                self.update_joypad_interrupt();
             }
-            0xFF00 => self.joypad.write(value),
 
             // APU
             0xFF26 | 0xFF25 | 0xFF24 => self.apu.global.write(address as u16, value),
@@ -209,14 +277,77 @@ impl MemoryBus {
         return self.read_byte(address) as u16 | (self.read_byte(address + 1) as u16) << 8;
     }
 
+    // 次の M-cycle から 160 M-cycle かけて転送する。転送中に書き込むと最初からやり直す
     pub fn do_dma_transfer(&mut self, value: u8) {
         self.ppu.dma = value;
-        let address = (value as u16) << 8;
-        for i in 0x00..=0x9F {
-            let value = self.read_byte(address + i);
-            self.write_byte(0xFE00 + i, value);
+        // 0xE000 以降は WRAM のミラー
+        let source = match (value as u16) << 8 {
+            address @ 0xE000..=0xFFFF => address - 0x2000,
+            address => address,
+        };
+        self.oam_dma = Some(OamDma {
+            source,
+            index: 0,
+            delay: 1,
+            blocking: self.oam_dma.is_some_and(|dma| dma.blocking),
+        });
+    }
+
+    fn oam_blocked(&self, address: u16) -> bool {
+        (0xFE00..=0xFE9F).contains(&address) && self.oam_dma.is_some_and(|dma| dma.blocking)
+    }
+
+    // 1 M-cycle (4 サイクル) 進める
+    pub fn tick(&mut self) {
+        self.cycles += 4;
+
+        self.timer.tick();
+        if self.timer.take_interrupt() {
+            self.request_interrupt(2);
+        }
+
+        self.tick_oam_dma();
+
+        match self.ppu.update(4, self.high_speed_mode) {
+            PPUInterrupt::NONE => {}
+            PPUInterrupt::VBALNK => self.request_interrupt(0),
+            PPUInterrupt::LCD => self.request_interrupt(1),
+        }
+
+        self.apu.update(self.timer.div(), self.high_speed_mode);
+    }
+
+    // 命令の中でメモリにアクセスしない M-cycle の分を進める
+    pub fn tick_cycles(&mut self, cycles: u16) {
+        for _ in 0..cycles / 4 {
+            self.tick();
         }
-        // FIXME 160サイクルかかる
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn request_interrupt(&mut self, id: u8) {
+        self.memory[0xFF0F] |= 0x01 << id;
+    }
+
+    fn tick_oam_dma(&mut self) {
+        let Some(mut dma) = self.oam_dma else {
+            return;
+        };
+        if dma.delay > 0 {
+            dma.delay -= 1;
+        } else if dma.index == 0xA0 {
+            self.oam_dma = None;
+            return;
+        } else {
+            let value = self.peek_byte(dma.source + dma.index);
+            self.ppu.write_oam(0xFE00 + dma.index as usize, value);
+            dma.index += 1;
+            dma.blocking = true;
+        }
+        self.oam_dma = Some(dma);
     }
 
     pub fn do_hdma_transfer(&mut self, value: u8) {
@@ -227,25 +358,53 @@ impl MemoryBus {
         // 実際の転送サイズを計算する
         let size = (size as u16 + 1) * 16;
 
-        // mode = true ==> HBlank DMA, false => 汎用DMA
-        let mode = self.ppu.hdma5 & 0x80 != 0;
-
+        // bit 7 が 1 なら HBlank DMA, 0 なら汎用DMA
         // 汎用DMAで処理を行う。
         // FIXME HBlank DMAは必要に応じて実装する。（必要ないかもしれない。。。）
 
         let src = ((self.ppu.hdma1 as u16) << 8 | self.ppu.hdma2 as u16) & 0xFFF0;
         let dest = (((self.ppu.hdma3 as u16) << 8 | self.ppu.hdma4 as u16) & 0x1FF0) | 0x8000;
 
-        println!("HDMA mode: {} s{:04X} d{:04X} {}", mode, src, dest, size);
-
         for i in 0..size {
-            let v = self.read_byte(src + i);
-            self.write_byte(dest + i, v);
+            let v = self.peek_byte(src + i);
+            self.poke_byte(dest + i, v);
         }
 
         self.ppu.hdma5 = 0xFF;
     }
 
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.joypad.set_buttons(buttons);
+        self.update_joypad_interrupt();
+    }
+
+    // ボタンが押されたら IF のビット4を立てる
+    fn update_joypad_interrupt(&mut self) {
+        if self.joypad.take_interrupt() {
+            self.request_interrupt(4);
+        }
+    }
+
+    // GameShark のコード。バンクの指定がある場合は、そのバンクに書き込む
+    pub fn write_game_shark(&mut self, code: &GameShark) {
+        let svbk = self.svbk;
+        if let Some(bank) = code.bank {
+            self.svbk = bank;
+        }
+        self.poke_byte(code.address, code.value);
+        self.svbk = svbk;
+    }
+
+    // WRAM のすべてのバンク (バンク N は N * 0x1000 から)
+    pub fn wram(&self) -> &[u8] {
+        &self.wram
+    }
+
+    // 0xFF80-0xFFFE
+    pub fn hram(&self) -> &[u8] {
+        &self.memory[0xFF80..0xFFFF]
+    }
+
     fn read_wram(&mut self, address: u16) -> u8 {
         match address {
             0xC000..=0xCFFF => self.wram[address as usize - 0xC000],
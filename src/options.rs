@@ -0,0 +1,281 @@
+use std::path::PathBuf;
+
+use gameboy_emulator::cartridge::{PatchFile, SaveMismatch};
+use gameboy_emulator::cpu::HardwareMode;
+
+pub const USAGE: &str = "usage: gameboy_emulator [OPTIONS] <ROM>
+
+options:
+    --scale <N>          window scale (default: 3)
+    --mode <dmg|cgb>     hardware to emulate (default: cgb)
+    --mute               disable audio output
+    --debug-windows      show BG tile windows
+    --debugger           read debugger commands from the terminal (type \"help\")
+    --entry <NAME>       file to load from a .zip (default: the first .gb/.gbc)
+    --patch <FILE>       apply an IPS/UPS/BPS patch (default: a same-named patch next to the ROM)
+    --no-patch           do not apply a patch
+    --save-dir <DIR>     directory for save files (default: next to the ROM)
+    --save-mismatch <fail|resize|backup>
+                         what to do when the save file size does not match (default: ask)
+    --import-sav <FILE>  load battery RAM from a .sav file of another emulator
+    --export-sav <FILE>  write battery RAM as a .sav file on exit
+    --autosave <SECONDS> save battery RAM this often when it has changed, 0 to disable (default: 10)
+    --cheats <FILE>      load cheat codes (default: <save file name>.cht if it exists)
+    --rewind-interval <FRAMES>
+                         record a rewind point every FRAMES frames (default: 2)
+    --rewind-buffer <MB> memory for rewind, 0 to disable (default: 64)
+    -h, --help           print this help";
+
+#[derive(Debug)]
+pub struct Options {
+    pub rom: PathBuf,
+    pub scale: u32,
+    pub mode: HardwareMode,
+    pub mute: bool,
+    pub debug_windows: bool,
+    pub debugger: bool,
+    pub entry: Option<String>,
+    pub patch: PatchFile,
+    pub save_dir: Option<PathBuf>,
+    pub save_mismatch: Option<SaveMismatch>, // None ならダイアログで聞く
+    pub import_sav: Option<PathBuf>,
+    pub export_sav: Option<PathBuf>,
+    pub autosave: u64, // 秒
+    pub cheats: Option<PathBuf>,
+    pub rewind_interval: u32,
+    pub rewind_buffer: usize, // byte
+}
+
+impl Options {
+    // -h / --help の場合は None
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Self>, String> {
+        let mut rom = None;
+        let mut scale = 3;
+        let mut mode = HardwareMode::CGB;
+        let mut mute = false;
+        let mut debug_windows = false;
+        let mut debugger = false;
+        let mut entry = None;
+        let mut patch = PatchFile::Auto;
+        let mut save_dir = None;
+        let mut save_mismatch = None;
+        let mut import_sav = None;
+        let mut export_sav = None;
+        let mut autosave = 10;
+        let mut cheats = None;
+        let mut rewind_interval = 2;
+        let mut rewind_buffer = 64;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--scale" => {
+                    let value = Self::value(&mut args, "--scale")?;
+                    scale = match value.parse::<u32>() {
+                        Ok(n) if n > 0 => n,
+                        _ => return Err(format!("invalid scale: {}", value)),
+                    };
+                }
+                "--mode" => {
+                    let value = Self::value(&mut args, "--mode")?;
+                    mode = match value.to_ascii_lowercase().as_str() {
+                        "dmg" => HardwareMode::DMG,
+                        "cgb" => HardwareMode::CGB,
+                        _ => return Err(format!("invalid mode: {} (dmg or cgb)", value)),
+                    };
+                }
+                "--mute" => mute = true,
+                "--debug-windows" => debug_windows = true,
+                "--debugger" => debugger = true,
+                "--entry" => {
+                    let value = Self::value(&mut args, "--entry")?;
+                    entry = Some(value);
+                }
+                "--patch" => {
+                    let value = Self::value(&mut args, "--patch")?;
+                    let path = PathBuf::from(value);
+                    if !path.is_file() {
+                        return Err(format!("patch file not found: {}", path.display()));
+                    }
+                    patch = PatchFile::Path(path);
+                }
+                "--no-patch" => patch = PatchFile::Off,
+                "--save-dir" => {
+                    let value = Self::value(&mut args, "--save-dir")?;
+                    let dir = PathBuf::from(value);
+                    if !dir.is_dir() {
+                        return Err(format!("save directory not found: {}", dir.display()));
+                    }
+                    save_dir = Some(dir);
+                }
+                "--save-mismatch" => {
+                    let value = Self::value(&mut args, "--save-mismatch")?;
+                    save_mismatch = Some(match value.to_ascii_lowercase().as_str() {
+                        "fail" => SaveMismatch::Fail,
+                        "resize" => SaveMismatch::Resize,
+                        "backup" => SaveMismatch::Backup,
+                        _ => {
+                            return Err(format!(
+                                "invalid save mismatch: {} (fail, resize or backup)",
+                                value
+                            ))
+                        }
+                    });
+                }
+                "--import-sav" => {
+                    let value = Self::value(&mut args, "--import-sav")?;
+                    let path = PathBuf::from(value);
+                    if !path.is_file() {
+                        return Err(format!("save file not found: {}", path.display()));
+                    }
+                    import_sav = Some(path);
+                }
+                "--export-sav" => {
+                    export_sav = Some(PathBuf::from(Self::value(&mut args, "--export-sav")?))
+                }
+                "--autosave" => {
+                    let value = Self::value(&mut args, "--autosave")?;
+                    autosave = value
+                        .parse::<u64>()
+                        .map_err(|_| format!("invalid autosave interval: {}", value))?;
+                }
+                "--cheats" => {
+                    let value = Self::value(&mut args, "--cheats")?;
+                    let path = PathBuf::from(value);
+                    if !path.is_file() {
+                        return Err(format!("cheat file not found: {}", path.display()));
+                    }
+                    cheats = Some(path);
+                }
+                "--rewind-interval" => {
+                    let value = Self::value(&mut args, "--rewind-interval")?;
+                    rewind_interval = match value.parse::<u32>() {
+                        Ok(n) if n > 0 => n,
+                        _ => return Err(format!("invalid rewind interval: {}", value)),
+                    };
+                }
+                "--rewind-buffer" => {
+                    let value = Self::value(&mut args, "--rewind-buffer")?;
+                    rewind_buffer = value
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid rewind buffer size: {}", value))?;
+                }
+                _ if arg.starts_with('-') => {
+                    return Err(format!("unknown option: {}\n\n{}", arg, USAGE))
+                }
+                _ => {
+                    if rom.is_some() {
+                        return Err(format!("unexpected argument: {}\n\n{}", arg, USAGE));
+                    }
+                    rom = Some(PathBuf::from(arg));
+                }
+            }
+        }
+
+        let rom = match rom {
+            Some(rom) => rom,
+            None => return Err(format!("no ROM file given\n\n{}", USAGE)),
+        };
+        if !rom.is_file() {
+            return Err(format!("ROM file not found: {}", rom.display()));
+        }
+
+        Ok(Some(Options {
+            rom,
+            scale,
+            mode,
+            mute,
+            debug_windows,
+            debugger,
+            entry,
+            patch,
+            save_dir,
+            save_mismatch,
+            import_sav,
+            export_sav,
+            autosave,
+            cheats,
+            rewind_interval,
+            rewind_buffer: rewind_buffer * 1024 * 1024,
+        }))
+    }
+
+    fn value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<String, String> {
+        args.next()
+            .ok_or_else(|| format!("missing value for {}", name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    // 存在するファイルなら何でもよい
+    const ROM: &str = "Cargo.toml";
+
+    #[test]
+    fn test_defaults() {
+        let options = parse(&[ROM]).unwrap().unwrap();
+        assert_eq!(options.rom, PathBuf::from(ROM));
+        assert_eq!(options.scale, 3);
+        assert_eq!(options.mode, HardwareMode::CGB);
+        assert!(!options.mute);
+        assert!(options.save_mismatch.is_none());
+        assert_eq!(options.autosave, 10);
+        assert_eq!(options.rewind_interval, 2);
+        assert_eq!(options.rewind_buffer, 64 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_options() {
+        let options = parse(&[
+            "--scale",
+            "2",
+            "--mode",
+            "DMG",
+            "--mute",
+            "--save-mismatch",
+            "backup",
+            "--rewind-buffer",
+            "0",
+            ROM,
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.scale, 2);
+        assert_eq!(options.mode, HardwareMode::DMG);
+        assert!(options.mute);
+        assert_eq!(options.save_mismatch, Some(SaveMismatch::Backup));
+        assert_eq!(options.rewind_buffer, 0);
+    }
+
+    #[test]
+    fn test_help() {
+        assert!(parse(&["--help"]).unwrap().is_none());
+        assert!(parse(&["--scale", "2", "-h", ROM]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse(&[]).unwrap_err().starts_with("no ROM file given"));
+        assert!(parse(&["--scale", "0", ROM])
+            .unwrap_err()
+            .starts_with("invalid scale"));
+        assert!(parse(&["--mode"])
+            .unwrap_err()
+            .starts_with("missing value for --mode"));
+        assert!(parse(&["--unknown", ROM])
+            .unwrap_err()
+            .starts_with("unknown option"));
+        assert!(parse(&[ROM, ROM])
+            .unwrap_err()
+            .starts_with("unexpected argument"));
+        assert!(parse(&["no_such_rom.gb"])
+            .unwrap_err()
+            .starts_with("ROM file not found"));
+    }
+}
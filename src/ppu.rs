@@ -1,3 +1,5 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
 pub const VRAM_BEGIN: usize = 0x8000;
 pub const VRAM_END: usize = 0x9FFF;
 pub const VRAM_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
@@ -349,7 +351,12 @@ impl PPU {
             return;
         }
 
-        // タイルセットの更新
+        self.update_tile(bank, index);
+    }
+
+    // タイルセットの更新
+    fn update_tile(&mut self, bank: usize, index: usize) {
+        let idx = index + 0x2000 * bank;
         let normalized_index = idx & 0xFFFE;
         let byte1 = self.vram[normalized_index];
         let byte2 = self.vram[normalized_index + 1];
@@ -372,7 +379,10 @@ impl PPU {
 
     pub fn write_oam(&mut self, address: usize, value: u8) {
         self.oam[address - 0xFE00] = value;
+        self.update_sprites();
+    }
 
+    fn update_sprites(&mut self) {
         for n in 0..40 {
             let i = n * 4;
             let y = self.oam[i];
@@ -391,6 +401,86 @@ impl PPU {
         self.oam[address - 0xFE00]
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.vram);
+        w.write_u8(self.ly);
+        w.write_u8(self.lyc);
+        w.write_u8(u8::from(self.control));
+        w.write_u8(u8::from(self.status));
+        w.write_u8(self.scy);
+        w.write_u8(self.scx);
+        w.write_u8(self.dma);
+        w.write_u8(self.bgp);
+        w.write_u8(self.obp0);
+        w.write_u8(self.obp1);
+        w.write_u8(self.wy);
+        w.write_u8(self.wx);
+        w.write_bytes(self.oam.as_ref());
+        w.write_u16(self.scanline_counter);
+        w.write_bytes(&self.frame);
+        w.write_u8(self.last_ly);
+        w.write_u8(self.window_line);
+        w.write_bool(self.opri);
+        w.write_u16(self.cycles);
+        w.write_u8(self.hdma1);
+        w.write_u8(self.hdma2);
+        w.write_u8(self.hdma3);
+        w.write_u8(self.hdma4);
+        w.write_u8(self.hdma5);
+        w.write_u8(self.vbk);
+        w.write_u8(self.bcps);
+        w.write_bytes(self.bg_palette_raw.as_ref());
+        w.write_u8(self.ocps);
+        w.write_bytes(self.sprite_palette_raw.as_ref());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.read_bytes(&mut self.vram)?;
+        self.ly = r.read_u8()?;
+        self.lyc = r.read_u8()?;
+        self.control = LcdControlRegisters::from(r.read_u8()?);
+        self.status = LcdStatusRegisters::from(r.read_u8()?);
+        self.scy = r.read_u8()?;
+        self.scx = r.read_u8()?;
+        self.dma = r.read_u8()?;
+        self.bgp = r.read_u8()?;
+        self.obp0 = r.read_u8()?;
+        self.obp1 = r.read_u8()?;
+        self.wy = r.read_u8()?;
+        self.wx = r.read_u8()?;
+        r.read_bytes(self.oam.as_mut())?;
+        self.scanline_counter = r.read_u16()?;
+        r.read_bytes(&mut self.frame)?;
+        self.last_ly = r.read_u8()?;
+        self.window_line = r.read_u8()?;
+        self.opri = r.read_bool()?;
+        self.cycles = r.read_u16()?;
+        self.hdma1 = r.read_u8()?;
+        self.hdma2 = r.read_u8()?;
+        self.hdma3 = r.read_u8()?;
+        self.hdma4 = r.read_u8()?;
+        self.hdma5 = r.read_u8()?;
+        self.vbk = r.read_u8()?;
+        self.bcps = r.read_u8()?;
+        r.read_bytes(self.bg_palette_raw.as_mut())?;
+        self.ocps = r.read_u8()?;
+        r.read_bytes(self.sprite_palette_raw.as_mut())?;
+
+        // VRAM, OAM, パレットから作っているデータを作り直す
+        for bank in 0..2 {
+            for index in (0..0x1800).step_by(2) {
+                self.update_tile(bank, index);
+            }
+        }
+        self.update_sprites();
+        for addr in (0..64).step_by(2) {
+            self.update_bg_palette(addr);
+            self.update_sprite_palette(addr);
+        }
+        self.frame_updated = false;
+        Ok(())
+    }
+
     pub fn update(&mut self, cycles: u16, high_speed_mode: bool) -> PPUInterrupt {
         if !self.control.enabled {
             return PPUInterrupt::NONE;
@@ -889,7 +979,14 @@ impl PPU {
         let addr = self.bcps & 0x3F;
         let auto_increment = self.bcps & 0x80 != 0;
         self.bg_palette_raw[addr as usize] = value;
+        self.update_bg_palette(addr);
 
+        if auto_increment {
+            self.bcps = (self.bcps + 1) & 0xBF;
+        }
+    }
+
+    fn update_bg_palette(&mut self, addr: u8) {
         let lower = (addr & 0x3E) as usize;
         let palette =
             self.bg_palette_raw[lower] as u16 | ((self.bg_palette_raw[lower + 1] as u16) << 8);
@@ -905,10 +1002,6 @@ impl PPU {
         let color_index = (addr & 0x06) >> 1;
         let palette_index = (addr & 0x38) >> 3;
         self.bg_palette[palette_index as usize][color_index as usize] = [red, green, blue];
-
-        if auto_increment {
-            self.bcps = (self.bcps + 1) & 0xBF;
-        }
     }
 
     pub fn read_bg_palette(&mut self) -> u8 {
@@ -920,7 +1013,14 @@ impl PPU {
         let addr = self.ocps & 0x3F;
         let auto_increment = self.ocps & 0x80 != 0;
         self.sprite_palette_raw[addr as usize] = value;
+        self.update_sprite_palette(addr);
+
+        if auto_increment {
+            self.ocps = (self.ocps + 1) & 0xBF;
+        }
+    }
 
+    fn update_sprite_palette(&mut self, addr: u8) {
         let lower = (addr & 0x3E) as usize;
         let palette = self.sprite_palette_raw[lower] as u16
             | ((self.sprite_palette_raw[lower + 1] as u16) << 8);
@@ -930,10 +1030,6 @@ impl PPU {
         let color_index = (addr & 0x06) >> 1;
         let palette_index = (addr & 0x38) >> 3;
         self.sprite_palette[palette_index as usize][color_index as usize] = [red, green, blue];
-
-        if auto_increment {
-            self.ocps = (self.ocps + 1) & 0xBF;
-        }
     }
 
     pub fn read_sprite_palette(&mut self) -> u8 {
@@ -0,0 +1,328 @@
+// RAM サーチ (チートを作るための値の絞り込み)
+//
+// WRAM (CGB の 8 バンクすべて。DMG では 2 バンク), HRAM, カートリッジの RAM を対象にする。
+// new で今の値を覚えておき、filter のたびに前回の値と比べて候補を絞る。
+//
+//   let mut search = RamSearch::new(&emulator, Width::Byte);
+//   emulator.run_frame(); // ゲームの中で値を変える
+//   search.filter(&emulator, Filter::Decreased);
+//   for (location, value) in search.results(&emulator) { ... }
+
+use std::fmt;
+
+use crate::cheat::GameShark;
+use crate::cpu::HardwareMode;
+use crate::Emulator;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Region {
+    // 0xC000-0xDFFF。バンク N は N * 0x1000 から
+    Wram,
+    // 0xFF80-0xFFFE
+    Hram,
+    // 0xA000-0xBFFF。バンク N は N * 0x2000 から
+    CartridgeRam,
+}
+
+impl Region {
+    fn bank_size(&self) -> usize {
+        match self {
+            Region::Wram => 0x1000,
+            Region::Hram => 0x7F,
+            Region::CartridgeRam => 0x2000,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Location {
+    pub region: Region,
+    // 領域の先頭からの位置
+    pub offset: usize,
+}
+
+impl Location {
+    pub fn bank(&self) -> usize {
+        self.offset / self.region.bank_size()
+    }
+
+    // CPU から見たアドレス
+    pub fn address(&self) -> u16 {
+        match self.region {
+            Region::Wram if self.offset < 0x1000 => 0xC000 + self.offset as u16,
+            Region::Wram => 0xD000 + (self.offset % 0x1000) as u16,
+            Region::Hram => 0xFF80 + self.offset as u16,
+            Region::CartridgeRam => 0xA000 + (self.offset % 0x2000) as u16,
+        }
+    }
+
+    // この場所に値を書き込み続ける GameShark のコード。WRAM 以外は None
+    pub fn game_shark(&self, value: u8) -> Option<GameShark> {
+        match self.region {
+            Region::Wram => Some(GameShark {
+                bank: if self.offset < 0x1000 {
+                    None
+                } else {
+                    Some(self.bank() as u8)
+                },
+                address: self.address(),
+                value,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.region {
+            Region::Wram => write!(f, "WRAM {}:{:04X}", self.bank(), self.address()),
+            Region::Hram => write!(f, "HRAM {:04X}", self.address()),
+            Region::CartridgeRam => write!(f, "SRAM {}:{:04X}", self.bank(), self.address()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Width {
+    Byte,
+    // リトルエンディアンの 16bit
+    Word,
+}
+
+impl Width {
+    fn size(&self) -> usize {
+        match self {
+            Width::Byte => 1,
+            Width::Word => 2,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Filter {
+    // 前回から変わっていない
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(u16),
+}
+
+impl Filter {
+    fn matches(&self, previous: u16, current: u16) -> bool {
+        match self {
+            Filter::Equal => current == previous,
+            Filter::Changed => current != previous,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+            Filter::Value(value) => current == *value,
+        }
+    }
+}
+
+// 検索する領域の値
+struct Snapshot {
+    wram: Vec<u8>,
+    hram: Vec<u8>,
+    cartridge_ram: Vec<u8>,
+}
+
+impl Snapshot {
+    fn new(emulator: &Emulator) -> Self {
+        let bus = &emulator.cpu().bus;
+        // DMG にはバンク 2-7 がない
+        let wram = if emulator.hardware_mode() == HardwareMode::DMG {
+            &bus.wram()[..0x2000]
+        } else {
+            bus.wram()
+        };
+        Snapshot {
+            wram: wram.to_vec(),
+            hram: bus.hram().to_vec(),
+            cartridge_ram: bus.cartridge.ram().to_vec(),
+        }
+    }
+
+    fn region(&self, region: Region) -> &[u8] {
+        match region {
+            Region::Wram => &self.wram,
+            Region::Hram => &self.hram,
+            Region::CartridgeRam => &self.cartridge_ram,
+        }
+    }
+
+    fn value(&self, location: &Location, width: Width) -> u16 {
+        let data = self.region(location.region);
+        match width {
+            Width::Byte => data[location.offset] as u16,
+            Width::Word => (data[location.offset + 1] as u16) << 8 | data[location.offset] as u16,
+        }
+    }
+}
+
+pub struct RamSearch {
+    width: Width,
+    previous: Snapshot,
+    candidates: Vec<Location>,
+}
+
+impl RamSearch {
+    // 今の値を覚えて、すべての場所を候補にする
+    pub fn new(emulator: &Emulator, width: Width) -> Self {
+        let previous = Snapshot::new(emulator);
+        let mut candidates = Vec::new();
+        for region in [Region::Wram, Region::Hram, Region::CartridgeRam] {
+            let size = previous.region(region).len();
+            let bank_size = region.bank_size();
+            // 16bit の場合、バンクをまたぐ場所は除く
+            candidates.extend(
+                (0..size)
+                    .filter(|offset| offset % bank_size + width.size() <= bank_size)
+                    .map(|offset| Location { region, offset }),
+            );
+        }
+        RamSearch {
+            width,
+            previous,
+            candidates,
+        }
+    }
+
+    pub fn width(&self) -> Width {
+        self.width
+    }
+
+    // 条件に合う場所だけを残し、今の値を次の比較に使う
+    pub fn filter(&mut self, emulator: &Emulator, filter: Filter) {
+        let current = Snapshot::new(emulator);
+        let width = self.width;
+        let previous = &self.previous;
+        self.candidates.retain(|location| {
+            filter.matches(
+                previous.value(location, width),
+                current.value(location, width),
+            )
+        });
+        self.previous = current;
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    pub fn candidates(&self) -> &[Location] {
+        &self.candidates
+    }
+
+    // 残っている場所と今の値
+    pub fn results(&self, emulator: &Emulator) -> Vec<(Location, u16)> {
+        let current = Snapshot::new(emulator);
+        self.candidates
+            .iter()
+            .map(|location| (*location, current.value(location, self.width)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::newEmulator;
+
+    #[test]
+    fn test_location() {
+        let location = Location {
+            region: Region::Wram,
+            offset: 0x32A0,
+        };
+        assert_eq!(location.bank(), 3);
+        assert_eq!(location.address(), 0xD2A0);
+        assert_eq!(location.to_string(), "WRAM 3:D2A0");
+        assert_eq!(
+            location.game_shark(0x63),
+            Some(GameShark {
+                bank: Some(3),
+                address: 0xD2A0,
+                value: 0x63,
+            })
+        );
+
+        let location = Location {
+            region: Region::CartridgeRam,
+            offset: 0x2010,
+        };
+        assert_eq!(location.to_string(), "SRAM 1:A010");
+        assert_eq!(location.game_shark(0), None);
+    }
+
+    #[test]
+    fn test_filter_byte() {
+        let mut emulator = newEmulator();
+        let bus = &mut emulator.cpu_mut().bus;
+        bus.write_byte(0xC100, 10);
+        bus.write_byte(0xFF70, 0x03);
+        bus.write_byte(0xD200, 10);
+        bus.write_byte(0xFF90, 10);
+
+        let mut search = RamSearch::new(&emulator, Width::Byte);
+        assert_eq!(search.len(), 0x8000 + 0x7F);
+
+        search.filter(&emulator, Filter::Value(10));
+        assert_eq!(search.len(), 3);
+
+        let bus = &mut emulator.cpu_mut().bus;
+        bus.write_byte(0xC100, 9);
+        bus.write_byte(0xD200, 11);
+        search.filter(&emulator, Filter::Changed);
+        assert_eq!(search.len(), 2);
+
+        let bus = &mut emulator.cpu_mut().bus;
+        bus.write_byte(0xC100, 8);
+        search.filter(&emulator, Filter::Decreased);
+        assert_eq!(
+            search.results(&emulator),
+            vec![(
+                Location {
+                    region: Region::Wram,
+                    offset: 0x0100,
+                },
+                8
+            )]
+        );
+    }
+
+    #[test]
+    fn test_dmg() {
+        let emulator = Emulator::new(Cartridge::for_test(), HardwareMode::DMG, 44100);
+        let search = RamSearch::new(&emulator, Width::Byte);
+        assert_eq!(search.len(), 0x2000 + 0x7F);
+        assert_eq!(search.candidates()[0x1FFF].to_string(), "WRAM 1:DFFF");
+    }
+
+    #[test]
+    fn test_filter_word() {
+        let mut emulator = newEmulator();
+        let mut search = RamSearch::new(&emulator, Width::Word);
+        // WRAM のバンクの終わりと HRAM の終わりは 16bit では読めない
+        assert_eq!(search.len(), 0x8000 - 8 + 0x7E);
+
+        let bus = &mut emulator.cpu_mut().bus;
+        bus.write_byte(0xC010, 0x34);
+        bus.write_byte(0xC011, 0x12);
+        // C00F, C010, C011 からの 16bit が増えている
+        search.filter(&emulator, Filter::Increased);
+        assert_eq!(search.len(), 3);
+        search.filter(&emulator, Filter::Equal);
+        assert_eq!(search.len(), 3);
+
+        search.filter(&emulator, Filter::Value(0x1234));
+        assert_eq!(search.candidates()[0].address(), 0xC010);
+        assert_eq!(search.len(), 1);
+    }
+}
@@ -0,0 +1,214 @@
+// 巻き戻し用のステートのリングバッファ
+//
+// 最新のステートだけをそのまま持ち、それより古いステートは
+// 「1つ新しいステートとのXOR」をランレングス圧縮したものを持つ。
+// フレーム間で変化するのはメモリのごく一部なので、ほとんどが0になってよく縮む。
+
+use std::collections::VecDeque;
+
+use crate::Emulator;
+
+pub struct Rewind {
+    interval: u32,
+    budget: usize,
+    frame_count: u32,
+    latest: Option<Vec<u8>>,
+    // front が一番古い
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+}
+
+impl Rewind {
+    // interval: 何フレームごとにステートを記録するか
+    // budget: 使ってよいメモリ (byte)
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frame_count: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    // 1フレームごとに呼ぶ
+    pub fn record(&mut self, emulator: &Emulator) {
+        if self.budget == 0 {
+            return;
+        }
+        self.frame_count += 1;
+        if self.frame_count < self.interval {
+            return;
+        }
+        self.frame_count = 0;
+        self.push(emulator.save_state());
+    }
+
+    // 1つ前のステートに戻す。戻せるステートがない場合は false
+    pub fn rewind(&mut self, emulator: &mut Emulator) -> bool {
+        match self.pop() {
+            Some(state) => {
+                self.frame_count = 0;
+                emulator.load_state(&state).is_ok()
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frame_count = 0;
+        self.latest = None;
+        self.deltas.clear();
+        self.deltas_size = 0;
+    }
+
+    // 記録しているステートの数
+    pub fn len(&self) -> usize {
+        match self.latest {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    // 使っているメモリ (byte)
+    pub fn size(&self) -> usize {
+        self.latest.as_ref().map_or(0, |state| state.len()) + self.deltas_size
+    }
+
+    fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            if latest.len() == state.len() {
+                let delta = compress(&latest, &state);
+                self.deltas_size += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // ステートのサイズが変わった (別のROMを読み込んだなど)
+                self.deltas.clear();
+                self.deltas_size = 0;
+            }
+        }
+        self.latest = Some(state);
+
+        while self.size() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => {
+                    // 最新のステートだけでも入りきらない
+                    self.latest = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            self.deltas_size -= delta.len();
+            self.latest = Some(decompress(&latest, &delta));
+        }
+        Some(latest)
+    }
+}
+
+// older ^ newer をランレングス圧縮する。
+//   [0の個数 (u16)] [そのままのバイトの個数 (u16)] [そのままのバイト...] の繰り返し
+fn compress(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < older.len() {
+        let mut zeros = 0;
+        while i < older.len() && older[i] == newer[i] && zeros < 0xFFFF {
+            zeros += 1;
+            i += 1;
+        }
+        let start = i;
+        while i < older.len() && older[i] != newer[i] && i - start < 0xFFFF {
+            i += 1;
+        }
+        out.extend_from_slice(&(zeros as u16).to_le_bytes());
+        out.extend_from_slice(&((i - start) as u16).to_le_bytes());
+        out.extend(
+            older[start..i]
+                .iter()
+                .zip(&newer[start..i])
+                .map(|(a, b)| a ^ b),
+        );
+    }
+    out
+}
+
+// compress の逆。newer と差分から older を作る
+fn decompress(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut older = newer.to_vec();
+    let mut i = 0;
+    let mut position = 0;
+    while position + 4 <= delta.len() {
+        let zeros = u16::from_le_bytes([delta[position], delta[position + 1]]) as usize;
+        let literals = u16::from_le_bytes([delta[position + 2], delta[position + 3]]) as usize;
+        position += 4;
+        i += zeros;
+        for (byte, x) in older[i..i + literals]
+            .iter_mut()
+            .zip(&delta[position..position + literals])
+        {
+            *byte ^= x;
+        }
+        i += literals;
+        position += literals;
+    }
+    older
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        let older = vec![0, 1, 2, 3, 4, 5, 6, 7];
+        let newer = vec![0, 1, 9, 3, 4, 5, 6, 8];
+        let delta = compress(&older, &newer);
+        assert_eq!(delta, vec![2, 0, 1, 0, 2 ^ 9, 4, 0, 1, 0, 7 ^ 8]);
+        assert_eq!(decompress(&newer, &delta), older);
+
+        let newer = vec![0; 0x20000];
+        let mut older = vec![0; 0x20000];
+        older[0x1FFFF] = 1;
+        assert_eq!(decompress(&newer, &compress(&older, &newer)), older);
+        assert!(compress(&newer, &newer).len() < 16);
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut rewind = Rewind::new(1, 1024);
+        rewind.push(vec![1; 100]);
+        rewind.push(vec![2; 100]);
+        rewind.push(vec![3; 100]);
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop(), Some(vec![3; 100]));
+        assert_eq!(rewind.pop(), Some(vec![2; 100]));
+        assert_eq!(rewind.pop(), Some(vec![1; 100]));
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut rewind = Rewind::new(1, 150);
+        for i in 0..10 {
+            rewind.push(vec![i; 100]);
+        }
+        assert!(rewind.size() <= 150);
+        assert_eq!(rewind.pop(), Some(vec![9; 100]));
+
+        // 最新のステートも入りきらない場合は何も持たない
+        let mut rewind = Rewind::new(1, 50);
+        rewind.push(vec![0; 100]);
+        assert!(rewind.is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+// ステートセーブのバイナリ形式
+//
+//   "GBST" (4byte)
+//   version (u16)
+//   ヘッダーチェックサム 0x014D (u8)
+//   グローバルチェックサム 0x014E-0x014F (u16)
+//   タイトル 0x0134-0x0143 (16byte)
+//   CPU -> MemoryBus -> Timer -> Cartridge -> PPU -> APU -> Joypad の順に各状態
+//   チート
+//
+// 数値はすべてリトルエンディアン。
+
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"GBST";
+pub const VERSION: u16 = 9;
+
+#[derive(Debug, PartialEq)]
+pub enum SaveStateError {
+    InvalidFormat,
+    UnsupportedVersion(u16),
+    WrongRom { expected: String, found: String },
+    UnexpectedEof,
+    SizeMismatch { expected: usize, found: usize },
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::InvalidFormat => write!(f, "not a save state file"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version: {}", version)
+            }
+            SaveStateError::WrongRom { expected, found } => write!(
+                f,
+                "save state is for another game (expected: {}, found: {})",
+                expected, found
+            ),
+            SaveStateError::UnexpectedEof => write!(f, "save state is truncated"),
+            SaveStateError::SizeMismatch { expected, found } => write!(
+                f,
+                "save state size is not match (expected: {}, found: {})",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+// ROMを識別するための情報
+#[derive(Debug, PartialEq, Clone)]
+pub struct RomId {
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub title: [u8; 16],
+}
+
+impl RomId {
+    pub fn from_rom(rom: &[u8]) -> Self {
+        let byte = |i: usize| rom.get(i).copied().unwrap_or(0);
+        let mut title = [0; 16];
+        for (i, c) in title.iter_mut().enumerate() {
+            *c = byte(0x0134 + i);
+        }
+        RomId {
+            header_checksum: byte(0x014D),
+            global_checksum: (byte(0x014E) as u16) << 8 | byte(0x014F) as u16,
+            title,
+        }
+    }
+
+    fn describe(&self) -> String {
+        let title: String = self
+            .title
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| {
+                if c.is_ascii_graphic() || c == b' ' {
+                    c as char
+                } else {
+                    '?'
+                }
+            })
+            .collect();
+        format!(
+            "{} [{:02X}/{:04X}]",
+            title.trim_end(),
+            self.header_checksum,
+            self.global_checksum
+        )
+    }
+}
+
+pub struct StateWriter {
+    buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom: &RomId) -> Self {
+        let mut writer = StateWriter { buffer: Vec::new() };
+        writer.write_bytes(MAGIC);
+        writer.write_u16(VERSION);
+        writer.write_u8(rom.header_checksum);
+        writer.write_u16(rom.global_checksum);
+        writer.write_bytes(&rom.title);
+        writer
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    // 固定長のデータ
+    pub fn write_bytes(&mut self, value: &[u8]) {
+        self.buffer.extend_from_slice(value);
+    }
+
+    // 長さ付きのデータ
+    pub fn write_vec(&mut self, value: &[u8]) {
+        self.write_u32(value.len() as u32);
+        self.write_bytes(value);
+    }
+
+    pub fn write_string(&mut self, value: &str) {
+        self.write_vec(value.as_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // ヘッダーを読んで、ROMとバージョンが一致するかを確認する
+    pub fn new(data: &'a [u8], rom: &RomId) -> Result<Self, SaveStateError> {
+        let mut reader = StateReader { data, position: 0 };
+        let mut magic = [0; 4];
+        reader
+            .read_bytes(&mut magic)
+            .map_err(|_| SaveStateError::InvalidFormat)?;
+        if &magic != MAGIC {
+            return Err(SaveStateError::InvalidFormat);
+        }
+        let version = reader.read_u16()?;
+        if version != VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        let header_checksum = reader.read_u8()?;
+        let global_checksum = reader.read_u16()?;
+        let mut title = [0; 16];
+        reader.read_bytes(&mut title)?;
+        let found = RomId {
+            header_checksum,
+            global_checksum,
+            title,
+        };
+        if &found != rom {
+            return Err(SaveStateError::WrongRom {
+                expected: rom.describe(),
+                found: found.describe(),
+            });
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, size: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() - self.position < size {
+            return Err(SaveStateError::UnexpectedEof);
+        }
+        let value = &self.data[self.position..self.position + size];
+        self.position += size;
+        Ok(value)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, SaveStateError> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    pub fn read_bytes(&mut self, value: &mut [u8]) -> Result<(), SaveStateError> {
+        value.copy_from_slice(self.take(value.len())?);
+        Ok(())
+    }
+
+    // write_vec で書いたデータを、同じ長さのバッファに読み込む
+    pub fn read_vec(&mut self, value: &mut [u8]) -> Result<(), SaveStateError> {
+        let size = self.read_u32()? as usize;
+        if size != value.len() {
+            return Err(SaveStateError::SizeMismatch {
+                expected: value.len(),
+                found: size,
+            });
+        }
+        self.read_bytes(value)
+    }
+
+    pub fn read_string(&mut self) -> Result<String, SaveStateError> {
+        let size = self.read_u32()? as usize;
+        let value = self.take(size)?;
+        String::from_utf8(value.to_vec()).map_err(|_| SaveStateError::InvalidFormat)
+    }
+
+    pub fn finish(&self) -> Result<(), SaveStateError> {
+        if self.position != self.data.len() {
+            return Err(SaveStateError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::newEmulator;
+
+    #[test]
+    fn test_round_trip() {
+        let mut emulator = newEmulator();
+        emulator.cpu_mut().registers.a = 0x12;
+        emulator.cpu_mut().bus.write_byte(0xC000, 0x34);
+        emulator.cpu_mut().bus.write_byte(0x8000, 0xFF);
+        let state = emulator.save_state();
+
+        emulator.cpu_mut().registers.a = 0x00;
+        emulator.cpu_mut().bus.write_byte(0xC000, 0x00);
+        emulator.cpu_mut().bus.write_byte(0x8000, 0x00);
+        emulator.load_state(&state).unwrap();
+
+        assert_eq!(emulator.cpu().registers.a, 0x12);
+        assert_eq!(emulator.read_memory(0xC000), 0x34);
+        assert_eq!(emulator.read_memory(0x8000), 0xFF);
+        assert_eq!(emulator.save_state(), state);
+    }
+
+    #[test]
+    fn test_wrong_rom() {
+        let mut emulator = newEmulator();
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        let state = StateWriter::new(&RomId::from_rom(&rom)).into_bytes();
+        assert!(matches!(
+            emulator.load_state(&state),
+            Err(SaveStateError::WrongRom { .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        let mut emulator = newEmulator();
+        emulator.cpu_mut().registers.a = 0x12;
+        let state = emulator.save_state();
+
+        assert_eq!(
+            emulator.load_state(b"hello"),
+            Err(SaveStateError::InvalidFormat)
+        );
+
+        let mut version = state.clone();
+        version[4] = 0xFF;
+        assert!(matches!(
+            emulator.load_state(&version),
+            Err(SaveStateError::UnsupportedVersion(_))
+        ));
+
+        // 途中で切れている場合は、読み込む前の状態のまま
+        emulator.cpu_mut().registers.a = 0x56;
+        assert_eq!(
+            emulator.load_state(&state[..state.len() - 1]),
+            Err(SaveStateError::UnexpectedEof)
+        );
+        assert_eq!(emulator.cpu().registers.a, 0x56);
+    }
+}
@@ -0,0 +1,235 @@
+// タイマー (DIV, TIMA, TMA, TAC)
+//
+// DIV は 16bit の内部カウンタの上位 8bit。
+// TIMA は TAC で選んだカウンタのビットが 1 から 0 になるたびに増える。
+//   00: bit 9 (4096Hz), 01: bit 3 (262144Hz), 10: bit 5 (65536Hz), 11: bit 7 (16384Hz)
+// TIMA があふれると 1 M-cycle の間は 0 のままで、次の M-cycle で TMA を読み込んで割り込みを要求する。
+
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA があふれて、次の M-cycle で TMA を読み込む
+    overflow: bool,
+    // この M-cycle で TMA を読み込んだ (TIMA への書き込みは無視される)
+    reloaded: bool,
+    interrupt: bool,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloaded: false,
+            interrupt: false,
+        }
+    }
+
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    // 1 M-cycle (4 サイクル) 進める
+    pub fn tick(&mut self) {
+        self.reloaded = false;
+        if self.overflow {
+            self.overflow = false;
+            self.reloaded = true;
+            self.tima = self.tma;
+            self.interrupt = true;
+        }
+
+        let input = self.input();
+        self.counter = self.counter.wrapping_add(4);
+        self.detect_falling_edge(input);
+    }
+
+    // 割り込みを要求していれば true を返し、要求を消す
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF04 => self.div(),
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => self.tac | 0xF8,
+            _ => panic!("should not reach."),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            // どの値を書いてもカウンタは 0 になる。選んでいるビットが 1 だった場合は TIMA が増える
+            0xFF04 => {
+                let input = self.input();
+                self.counter = 0;
+                self.detect_falling_edge(input);
+            }
+            0xFF05 => {
+                // あふれた直後に書き込むと TMA の読み込みは取り消される
+                if !self.reloaded {
+                    self.tima = value;
+                    self.overflow = false;
+                }
+            }
+            0xFF06 => {
+                self.tma = value;
+                if self.reloaded {
+                    self.tima = value;
+                }
+            }
+            0xFF07 => {
+                let input = self.input();
+                self.tac = value & 0x07;
+                self.detect_falling_edge(input);
+            }
+            _ => panic!("should not reach."),
+        }
+    }
+
+    // TAC で選んだビット (タイマーが止まっていれば常に false)
+    fn input(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn detect_falling_edge(&mut self, before: bool) {
+        if before && !self.input() {
+            self.increment();
+        }
+    }
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.overflow = true;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        w.write_u8(self.tima);
+        w.write_u8(self.tma);
+        w.write_u8(self.tac);
+        w.write_bool(self.overflow);
+        w.write_bool(self.reloaded);
+        w.write_bool(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.counter = r.read_u16()?;
+        self.tima = r.read_u8()?;
+        self.tma = r.read_u8()?;
+        self.tac = r.read_u8()?;
+        self.overflow = r.read_bool()?;
+        self.reloaded = r.read_bool()?;
+        self.interrupt = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tick(timer: &mut Timer, m_cycles: usize) {
+        for _ in 0..m_cycles {
+            timer.tick();
+        }
+    }
+
+    #[test]
+    fn test_div() {
+        let mut timer = Timer::new();
+        tick(&mut timer, 63);
+        assert_eq!(timer.read(0xFF04), 0);
+        tick(&mut timer, 1);
+        assert_eq!(timer.read(0xFF04), 1);
+        timer.write(0xFF04, 0x12);
+        assert_eq!(timer.read(0xFF04), 0);
+    }
+
+    #[test]
+    fn test_tima() {
+        let mut timer = Timer::new();
+        // 262144Hz: 4 M-cycle ごと
+        timer.write(0xFF07, 0x05);
+        tick(&mut timer, 3);
+        assert_eq!(timer.read(0xFF05), 0);
+        tick(&mut timer, 1);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        // 止めていれば増えない
+        timer.write(0xFF07, 0x01);
+        tick(&mut timer, 16);
+        assert_eq!(timer.read(0xFF05), 1);
+        assert_eq!(timer.read(0xFF07), 0xF9);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut timer = Timer::new();
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, 0x05);
+        tick(&mut timer, 4);
+        // 1 M-cycle の間は 0
+        assert_eq!(timer.read(0xFF05), 0x00);
+        assert!(!timer.take_interrupt());
+        tick(&mut timer, 1);
+        assert_eq!(timer.read(0xFF05), 0x80);
+        assert!(timer.take_interrupt());
+
+        // 0 の間に書き込むと読み込みは取り消される
+        timer.write(0xFF04, 0);
+        timer.write(0xFF05, 0xFF);
+        tick(&mut timer, 4);
+        timer.write(0xFF05, 0x12);
+        tick(&mut timer, 1);
+        assert_eq!(timer.read(0xFF05), 0x12);
+        assert!(!timer.take_interrupt());
+
+        // 読み込んだ M-cycle の書き込みは無視される
+        timer.write(0xFF04, 0);
+        timer.write(0xFF05, 0xFF);
+        tick(&mut timer, 5);
+        timer.write(0xFF05, 0x12);
+        assert_eq!(timer.read(0xFF05), 0x80);
+    }
+
+    #[test]
+    fn test_falling_edge() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0x05);
+        tick(&mut timer, 2);
+        // bit 3 が 1 のときに DIV をリセットすると増える
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        // 止めたときも同じ
+        tick(&mut timer, 2);
+        timer.write(0xFF07, 0x01);
+        assert_eq!(timer.read(0xFF05), 2);
+    }
+}
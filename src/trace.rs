@@ -0,0 +1,137 @@
+// 命令ごとのトレース (gameboy-doctor の形式)
+//
+//   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+//
+// 命令を読み込む前の状態を 1 行ずつ書く。HALT や STOP で止まっている間は書かない。
+// 参照用のログとそのまま diff できる (tools/diff.py)。
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::cpu::CPU;
+
+pub struct Tracer {
+    writer: Box<dyn Write>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Tracer { writer }
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file))))
+    }
+
+    pub fn write(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        writeln!(self.writer, "{}", line(cpu))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.writer.flush().ok();
+    }
+}
+
+// PC から 4 byte を読むが、時間は進めない
+pub fn line(cpu: &mut CPU) -> String {
+    let pcmem: Vec<String> = (0..4)
+        .map(|i| format!("{:02X}", cpu.bus.peek_byte(cpu.pc.wrapping_add(i))))
+        .collect();
+    let r = &cpu.registers;
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+        r.a,
+        u8::from(r.f),
+        r.b,
+        r.c,
+        r.d,
+        r.e,
+        r.h,
+        r.l,
+        cpu.sp,
+        cpu.pc,
+        pcmem.join(",")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{tempDir, Cartridge};
+    use crate::cpu::{FlagsRegister, HardwareMode};
+    use crate::{newEmulator, EmulatorEvent};
+
+    #[test]
+    fn test_line() {
+        let mut cpu = CPU::new(Cartridge::for_test(), 44100, HardwareMode::DMG);
+        cpu.registers.a = 0x01;
+        cpu.registers.f = FlagsRegister::from(0xB0);
+        cpu.registers.c = 0x13;
+        cpu.registers.d = 0x00;
+        cpu.registers.e = 0xD8;
+        cpu.registers.h = 0x01;
+        cpu.registers.l = 0x4D;
+        cpu.pc = 0xC000;
+        for (i, byte) in [0x00, 0xC3, 0x13, 0x02].iter().enumerate() {
+            cpu.bus.poke_byte(0xC000 + i as u16, *byte);
+        }
+        let cycles = cpu.bus.cycles();
+        assert_eq!(
+            line(&mut cpu),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C000 PCMEM:00,C3,13,02"
+        );
+        assert_eq!(cpu.bus.cycles(), cycles);
+    }
+
+    #[test]
+    fn test_stop_trace() {
+        // Emulator を drop しなくても (process::exit の前でも) stop_trace で全部書き出される
+        let dir = tempDir("trace");
+        let path = dir.join("trace.log");
+        let mut emulator = newEmulator();
+        emulator.start_trace(&path).unwrap();
+        for _ in 0..10 {
+            emulator.cpu_mut().step();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        emulator.stop_trace().unwrap();
+        assert!(!emulator.is_tracing());
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 10);
+        assert!(emulator.stop_trace().is_ok());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    struct Broken {}
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_error() {
+        let mut emulator = newEmulator();
+        emulator
+            .cpu_mut()
+            .set_tracer(Some(Tracer::new(Box::new(Broken {}))));
+        emulator.run_frame();
+        assert!(!emulator.is_tracing());
+        assert_eq!(
+            emulator.take_events(),
+            vec![EmulatorEvent::TraceFailed(io::ErrorKind::BrokenPipe)]
+        );
+    }
+}
@@ -1,4 +1,8 @@
-import re
+# トレースのログ (gameboy-doctor の形式) を比べて、最初に違う行を表示する
+#
+#   gb-headless --trace a.log rom.gb
+#   python3 tools/diff.py a.log reference.log
+
 import sys
 
 a = sys.argv[1]
@@ -11,8 +15,8 @@ with open(b) as f:
   blines = f.readlines()
 
 for i, (a, b) in enumerate(zip(alines, blines)):
-  ainst = re.sub(r"AF.*", "", a)
-  binst = re.sub(r"AF.*", "", b)
-  if ainst != binst:
+  if a != b:
+    if i > 0:
+      print(f"{i}\n {alines[i - 1]}")
     print(f"{i + 1}\na:{a}\nb:{b}")
     break
@@ -0,0 +1,68 @@
+#!/bin/sh
+# テストROMを gb-headless で実行して、ROM ごとに ok / FAIL (ROM がなければ none) を表示する。
+# ROM は README の例と同じ配置 (rom/mooneye/ に Mooneye の acceptance) を想定している。
+#
+#   tools/test_roms.sh [ROM のディレクトリ]
+#
+# ひとつでも ok 以外があれば exit code 1 。
+
+ROM=${1:-rom}
+HEADLESS=target/release/gb-headless
+if ! cargo build -q --release --no-default-features --bin gb-headless 2> /dev/null; then
+    echo "gb-headless のビルドに失敗" >&2
+    exit 1
+fi
+
+status=0
+
+result() {
+    if [ "$1" -eq 0 ]; then
+        echo "ok   $2"
+    else
+        echo "FAIL $2"
+        status=1
+    fi
+}
+
+mooneye() {
+    if [ ! -f "$ROM/mooneye/$1" ]; then
+        echo "none mooneye/$1"
+        status=1
+        return
+    fi
+    "$HEADLESS" --until-mooneye "$ROM/mooneye/$1" > /dev/null 2>&1
+    result $? "mooneye/$1"
+}
+
+# Blargg のテストはシリアルに Passed を出す
+blargg() {
+    if [ ! -f "$ROM/$1" ]; then
+        echo "none $1"
+        status=1
+        return
+    fi
+    "$HEADLESS" --frames 3000 --until-serial Passed "$ROM/$1" > /dev/null 2>&1
+    result $? "$1"
+}
+
+# CPU の命令の中のメモリアクセスのタイミング
+mooneye push_timing.gb
+mooneye call_timing.gb
+mooneye call_cc_timing.gb
+mooneye rst_timing.gb
+mooneye ret_timing.gb
+mooneye ret_cc_timing.gb
+mooneye reti_timing.gb
+mooneye jp_timing.gb
+mooneye jp_cc_timing.gb
+mooneye add_sp_e_timing.gb
+mooneye ld_hl_sp_e_timing.gb
+
+# HALT と割り込み
+blargg halt_bug.gb
+mooneye ei_sequence.gb
+mooneye ei_timing.gb
+mooneye rapid_di_ei.gb
+mooneye interrupts/ie_push.gb
+
+exit $status